@@ -0,0 +1,68 @@
+//! Compares `ThreadPool` with `WorkStealingPool`. Run with
+//! `cargo bench --bench pools`.
+
+extern crate bookserver;
+
+use bookserver::{ThreadPool, WorkStealingPool};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 4;
+const RUNS: usize = 5;
+
+/// Submits `jobs` jobs that each spin for `work`, and times how long it takes
+/// until the last one finishes.
+fn time_jobs<S>(submit: &S, jobs: usize, work: Duration) -> Duration
+where
+    S: Fn(Box<dyn FnOnce() + Send>),
+{
+    let remaining = Arc::new(AtomicUsize::new(jobs));
+    let (done, finished) = mpsc::channel();
+    let started = Instant::now();
+    for _ in 0..jobs {
+        let remaining = Arc::clone(&remaining);
+        let done = done.clone();
+        submit(Box::new(move || {
+            let spin = Instant::now();
+            while spin.elapsed() < work {}
+            if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                done.send(()).unwrap();
+            }
+        }));
+    }
+    finished.recv().unwrap();
+    started.elapsed()
+}
+
+/// The median of a few runs, to smooth out the scheduler.
+fn median<F: FnMut() -> Duration>(mut run: F) -> Duration {
+    let mut times = (0..RUNS).map(|_| run()).collect::<Vec<_>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn compare(name: &str, jobs: usize, work: Duration) {
+    let pool = ThreadPool::new(THREADS);
+    let shared = median(|| time_jobs(&|job| pool.execute(job), jobs, work));
+    let stealing_pool = WorkStealingPool::new(THREADS);
+    let stealing = median(|| time_jobs(&|job| stealing_pool.execute(job), jobs, work));
+
+    println!(
+        "{:<28} ThreadPool {:>9.2?}   WorkStealingPool {:>9.2?}   ({:.2}x)",
+        name,
+        shared,
+        stealing,
+        shared.as_secs_f64() / stealing.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!("{} threads, median of {} runs", THREADS, RUNS);
+    compare("100000 tiny jobs", 100_000, Duration::from_secs(0));
+    compare("10000 1µs jobs", 10_000, Duration::from_micros(1));
+    compare("8 long jobs (50ms)", 8, Duration::from_millis(50));
+    compare("6 long jobs (50ms)", 6, Duration::from_millis(50));
+}
@@ -0,0 +1,217 @@
+//! Access logs in the Common and Combined Log Formats.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use date::Civil;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `host ident authuser [date] "request" status bytes`
+    Common,
+    /// Common, followed by `"referer" "user-agent"`.
+    Combined,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<LogFormat, String> {
+        match text {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            _ => Err(format!("Unknown log format '{}', expected 'common' or 'combined'", text)),
+        }
+    }
+}
+
+/// One served request, as the access log sees it.
+pub struct Entry<'a> {
+    pub remote: Option<IpAddr>,
+    pub time: SystemTime,
+    /// The request line, such as `GET /books?page=2 HTTP/1.1`, or `None`
+    /// when the request could not be parsed.
+    pub request_line: Option<&'a str>,
+    pub status: u16,
+    /// Body bytes sent.
+    pub bytes: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// From the first byte of the request to the end of the response.
+    pub latency: Duration,
+}
+
+/// Writes one line per request. Both formats end with the latency in
+/// microseconds, like Apache's `%D`.
+pub struct AccessLog {
+    format: LogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new<W: Write + Send + 'static>(writer: W, format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn to_file<P: AsRef<Path>>(path: P, format: LogFormat) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(file, format))
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let line = self.format_entry(entry);
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // A full disk should not take the server down with it.
+        if let Err(error) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+            warn!("Failed to write the access log: {}", error);
+        }
+    }
+
+    fn format_entry(&self, entry: &Entry) -> String {
+        let time = Civil::from_time(entry.time);
+        let mut line = format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{}\" {} {}",
+            entry.remote.map_or_else(|| String::from("-"), |remote| remote.to_string()),
+            time.day,
+            time.month_name(),
+            time.year,
+            time.hour,
+            time.minute,
+            time.second,
+            entry.request_line.map_or_else(|| String::from("-"), escape),
+            entry.status,
+            match entry.bytes {
+                0 => String::from("-"),
+                bytes => bytes.to_string(),
+            }
+        );
+        if self.format == LogFormat::Combined {
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                entry.referer.map_or_else(|| String::from("-"), escape),
+                entry.user_agent.map_or_else(|| String::from("-"), escape)
+            ));
+        }
+        line.push_str(&format!(" {}\n", entry.latency.as_micros()));
+        line
+    }
+}
+
+/// Escapes quotes, backslashes and control characters, so that a request
+/// cannot forge log lines.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    /// A writer the test can read back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn entry<'a>() -> Entry<'a> {
+        Entry {
+            remote: Some("127.0.0.1".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request_line: Some("GET /apache_pb.gif HTTP/1.0"),
+            status: 200,
+            bytes: 2326,
+            referer: Some("http://www.example.com/start.html"),
+            user_agent: Some("Mozilla/4.08 [en] (Win98; I ;Nav)"),
+            latency: Duration::from_micros(1532),
+        }
+    }
+
+    #[test]
+    fn writes_the_common_format() {
+        let buffer = Buffer::default();
+        let log = AccessLog::new(buffer.clone(), LogFormat::Common);
+        log.log(&entry());
+
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 1532\n",
+            buffer.text()
+        );
+    }
+
+    #[test]
+    fn writes_the_combined_format() {
+        let buffer = Buffer::default();
+        let log = AccessLog::new(buffer.clone(), LogFormat::Combined);
+        log.log(&entry());
+        log.log(&Entry {
+            remote: None,
+            request_line: None,
+            status: 400,
+            bytes: 0,
+            referer: None,
+            user_agent: None,
+            ..entry()
+        });
+
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 \
+             \"http://www.example.com/start.html\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\" 1532\n\
+             - - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 - \"-\" \"-\" 1532\n",
+            buffer.text()
+        );
+    }
+
+    #[test]
+    fn escapes_what_clients_send() {
+        let buffer = Buffer::default();
+        let log = AccessLog::new(buffer.clone(), LogFormat::Combined);
+        log.log(&Entry {
+            user_agent: Some("evil\" \"agent\\\u{1b}[31m"),
+            ..entry()
+        });
+
+        assert!(buffer.text().contains(" \"evil\\\" \\\"agent\\\\\\x1b[31m\" 1532\n"), "{}", buffer.text());
+    }
+
+    #[test]
+    fn parses_format_names() {
+        assert_eq!(Ok(LogFormat::Common), "common".parse());
+        assert_eq!(Ok(LogFormat::Combined), "combined".parse());
+        assert!("json".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,50 +1,238 @@
-use std::io::prelude::*;
-use std::net::TcpStream;
-use std::net::TcpListener;
-use std::fs::File;
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
 
 extern crate bookserver;
-use bookserver::ThreadPool;
+extern crate clap;
+extern crate env_logger;
+use bookserver::{Compression, Router, Server};
+use bookserver::access_log::{AccessLog, LogFormat};
+use bookserver::cgi::Cgi;
+use bookserver::files::StaticFiles;
+use bookserver::proxy::Proxy;
+use clap::{App, Arg, ArgMatches};
 
 use std::thread;
 use std::time::Duration;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    env_logger::init();
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    let matches = App::new("bookserver")
+        .about("Serves a directory of static files")
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .short("a")
+                .value_name("ADDRESS")
+                .env("BOOKSERVER_ADDRESS")
+                .default_value("127.0.0.1:7878")
+                .help("Where to listen"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .short("t")
+                .value_name("COUNT")
+                .env("BOOKSERVER_THREADS")
+                .default_value("4")
+                .help("How many connections are served at once"),
+        )
+        .arg(
+            Arg::with_name("root")
+                .long("root")
+                .short("r")
+                .value_name("DIR")
+                .env("BOOKSERVER_ROOT")
+                .default_value("public")
+                .help("The document root to serve files from"),
+        )
+        .arg(
+            Arg::with_name("listings")
+                .long("listings")
+                .help("Lists directories that have no index.html"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .help("Compresses text responses for clients that accept gzip or deflate"),
+        )
+        .arg(
+            Arg::with_name("compress-min-size")
+                .long("compress-min-size")
+                .value_name("BYTES")
+                .env("BOOKSERVER_COMPRESS_MIN_SIZE")
+                .default_value("1024")
+                .help("Smaller responses are sent uncompressed"),
+        )
+        .arg(
+            Arg::with_name("precompressed")
+                .long("precompressed")
+                .help("Serves FILE.gz instead of FILE to clients that accept gzip, when it exists"),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("PREFIX=ADDRESS")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Forwards requests under PREFIX to the server at ADDRESS, such as /api=127.0.0.1:9000"),
+        )
+        .arg(
+            Arg::with_name("proxy-timeout")
+                .long("proxy-timeout")
+                .value_name("SECONDS")
+                .env("BOOKSERVER_PROXY_TIMEOUT")
+                .default_value("30")
+                .help("How long a proxied server may go quiet before the answer is 504"),
+        )
+        .arg(
+            Arg::with_name("cgi-bin")
+                .long("cgi-bin")
+                .value_name("DIR")
+                .env("BOOKSERVER_CGI_BIN")
+                .help("Runs the executables in DIR as CGI scripts under /cgi-bin/"),
+        )
+        .arg(
+            Arg::with_name("cgi-timeout")
+                .long("cgi-timeout")
+                .value_name("SECONDS")
+                .env("BOOKSERVER_CGI_TIMEOUT")
+                .default_value("30")
+                .help("How long a CGI script may run"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .env("BOOKSERVER_IDLE_TIMEOUT")
+                .default_value("5")
+                .help("How long an idle connection is kept open"),
+        )
+        .arg(
+            Arg::with_name("header-timeout")
+                .long("header-timeout")
+                .value_name("SECONDS")
+                .env("BOOKSERVER_HEADER_TIMEOUT")
+                .default_value("10")
+                .help("How long a client may take to send the request headers"),
+        )
+        .arg(
+            Arg::with_name("write-timeout")
+                .long("write-timeout")
+                .value_name("SECONDS")
+                .env("BOOKSERVER_WRITE_TIMEOUT")
+                .default_value("30")
+                .help("How long sending a response may stall"),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .long("access-log")
+                .value_name("FILE")
+                .env("BOOKSERVER_ACCESS_LOG")
+                .help("Appends a line per request to FILE, or to stdout for '-'"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .env("BOOKSERVER_LOG_FORMAT")
+                .possible_values(&["common", "combined"])
+                .default_value("combined")
+                .help("The access log format"),
+        )
+        .get_matches();
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    let address: SocketAddr = parse(&matches, "address");
+    let threads: usize = parse(&matches, "threads");
+    if threads == 0 {
+        exit("'threads' must be at least 1");
     }
-}
+    let idle_timeout = Duration::from_secs(parse(&matches, "idle-timeout"));
+    let header_timeout = Duration::from_secs(parse(&matches, "header-timeout"));
+    let write_timeout = Duration::from_secs(parse(&matches, "write-timeout"));
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+    let root = matches.value_of("root").unwrap();
+    let files = StaticFiles::new(root).unwrap_or_else(|error| exit(format!("Cannot serve {}: {}", root, error)));
+    let files = files
+        .listings(matches.is_present("listings"))
+        .precompressed(matches.is_present("precompressed"));
+    let files = Arc::new(files);
+    let sleepy_files = Arc::clone(&files);
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    let mut proxy = Proxy::new().timeout(Duration::from_secs(parse(&matches, "proxy-timeout")));
+    for route in matches.values_of("proxy").into_iter().flatten() {
+        let (prefix, upstream) = match route.find('=') {
+            Some(index) if route.starts_with('/') => (&route[..index], &route[index + 1..]),
+            _ => exit(format!("Error parsing 'proxy' ({}): expected PREFIX=ADDRESS", route)),
+        };
+        proxy = proxy.route(prefix, upstream);
+    }
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
+    let cgi = matches.value_of("cgi-bin").map(|directory| {
+        Cgi::new(directory)
+            .unwrap_or_else(|error| exit(format!("Cannot run scripts from {}: {}", directory, error)))
+            .timeout(Duration::from_secs(parse(&matches, "cgi-timeout")))
+    });
 
-    let mut file = File::open(filename).unwrap();
-    let mut contents = String::new();
+    let router = Router::new()
+        .get("/sleep", move |request, _| {
+            thread::sleep(Duration::from_secs(5));
+            let mut request = request.clone();
+            request.path = String::from("/");
+            sleepy_files.serve(&request)
+        })
+        .fallback(move |request, _| {
+            cgi.as_ref()
+                .and_then(|cgi| cgi.handle(request))
+                .unwrap_or_else(|| files.serve(request))
+        });
 
-    file.read_to_string(&mut contents).unwrap();
+    let mut server = Server::new(router)
+        .proxy(proxy)
+        .threads(threads)
+        .idle_timeout(idle_timeout)
+        .header_timeout(header_timeout)
+        .write_timeout(write_timeout);
+    if let Some(path) = matches.value_of("access-log") {
+        let format: LogFormat = parse(&matches, "log-format");
+        let access_log = match path {
+            "-" => AccessLog::new(io::stdout(), format),
+            _ => AccessLog::to_file(path, format)
+                .unwrap_or_else(|error| exit(format!("Cannot open the access log {}: {}", path, error))),
+        };
+        server = server.access_log(access_log);
+    }
+    if matches.is_present("compress") {
+        server = server.compression(Compression {
+            min_size: parse(&matches, "compress-min-size"),
+            ..Compression::default()
+        });
+    }
 
-    let response = format!("{}{}", status_line, contents);
+    println!("Serving {} on http://{}", root, address);
+    if let Err(error) = server.listen(address) {
+        exit(format!("Cannot listen on {}: {}", address, error));
+    }
+}
+
+/// Parses the value of the option `name`, which has a default or is
+/// otherwise checked to be present.
+fn parse<T>(matches: &ArgMatches, name: &str) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse()
+        .unwrap_or_else(|error| exit(format!("Error parsing '{}' ({}): {}", name, value, error)))
+}
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+fn exit<M: Display>(message: M) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
@@ -0,0 +1,468 @@
+//! Running scripts through the Common Gateway Interface, RFC 3875.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use files::is_within;
+use request::{percent_decode, Request};
+use response::Response;
+
+/// How long a script may run.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Output beyond this is a broken script, not a response.
+const MAX_OUTPUT: u64 = 16 * 1024 * 1024;
+
+/// Runs the executables in a directory for requests under a prefix, such as
+/// `/cgi-bin/report.sh/extra?x=1` for `report.sh`.
+///
+/// The script gets the request body on stdin and a clean environment of
+/// CGI variables, and answers with headers, a blank line and the body on
+/// stdout. What it writes to stderr goes to the server log. A script that
+/// fails, exits with an error, writes no valid headers or runs past the
+/// timeout is answered with 500.
+pub struct Cgi {
+    directory: PathBuf,
+    prefix: String,
+    timeout: Duration,
+}
+
+impl Cgi {
+    /// Runs scripts from `directory`, which must be an existing directory,
+    /// for requests under `/cgi-bin`.
+    pub fn new<P: AsRef<Path>>(directory: P) -> io::Result<Cgi> {
+        let directory = fs::canonicalize(directory)?;
+        if !directory.is_dir() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "CGI directory is not a directory"));
+        }
+        Ok(Cgi {
+            directory,
+            prefix: String::from("/cgi-bin"),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// The path the scripts are under. Defaults to `/cgi-bin`.
+    pub fn prefix(mut self, prefix: &str) -> Cgi {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// How long a script may run before it is killed. Defaults to
+    /// `DEFAULT_TIMEOUT`.
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The script's response to `request`, or `None` if the path is not
+    /// under the prefix.
+    pub fn handle(&self, request: &Request) -> Option<Response> {
+        let rest = request.path.strip_prefix(self.prefix.as_str())?;
+        let rest = rest.strip_prefix('/')?;
+        let (name, path_info) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, ""),
+        };
+        Some(self.run(request, name, path_info))
+    }
+
+    fn run(&self, request: &Request, name: &str, path_info: &str) -> Response {
+        let (name, path_info) = match (percent_decode(name), percent_decode(path_info)) {
+            (Some(name), Some(path_info)) => (name, path_info),
+            _ => return Response::text(400, "Invalid path\n"),
+        };
+        let script = match self.script(&name) {
+            Ok(script) => script,
+            Err(response) => return response,
+        };
+
+        let started = Instant::now();
+        let mut command = Command::new(&script);
+        command
+            .current_dir(&self.directory)
+            .env_clear()
+            .envs(environment(request, &format!("{}/{}", self.prefix, name), &path_info))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = spawn(&mut command).and_then(|child| collect(child, &request.body, self.timeout));
+        let result = output.and_then(|output| parse_output(&output));
+        debug!("CGI script {} took {:?}", name, started.elapsed());
+        result.unwrap_or_else(|error| {
+            warn!("CGI script {} failed: {}", name, error);
+            Response::text(500, "Internal server error\n")
+        })
+    }
+
+    /// The executable called `name`, which must be a file right in the
+    /// directory.
+    fn script(&self, name: &str) -> Result<PathBuf, Response> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') || name.contains('\0')
+        {
+            return Err(Response::text(404, "Not found\n"));
+        }
+        let path = self.directory.join(name);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Err(Response::text(404, "Not found\n")),
+        };
+        if !is_within(&self.directory, &path).unwrap_or(false) {
+            return Err(Response::text(404, "Not found\n"));
+        }
+        if !is_executable(&metadata) {
+            return Err(Response::text(403, "Forbidden\n"));
+        }
+        Ok(path)
+    }
+}
+
+/// Spawns `command`, retrying while the script is still open for writing
+/// somewhere, as happens right after it was installed.
+fn spawn(command: &mut Command) -> io::Result<Child> {
+    let mut attempts = 0;
+    loop {
+        match command.spawn() {
+            Err(ref error) if error.kind() == ErrorKind::ExecutableFileBusy && attempts < 5 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    true
+}
+
+/// The CGI meta-variables for `request`, plus a `PATH` to find
+/// interpreters on.
+fn environment(request: &Request, script_name: &str, path_info: &str) -> Vec<(String, String)> {
+    // The name is the one the client used; the port is the one it reached,
+    // whatever the Host header says.
+    let host = request.header("Host").unwrap_or("localhost");
+    let server_name = match host.rfind(':') {
+        Some(index) if !host.ends_with(']') => &host[..index],
+        _ => host,
+    };
+    let server_port = request.local.map_or(80, |local| local.port());
+    let mut variables = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", format!("bookserver/{}", env!("CARGO_PKG_VERSION"))),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.clone()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+        (
+            "PATH",
+            env::var("PATH").unwrap_or_else(|_| String::from("/usr/local/bin:/usr/bin:/bin")),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+    if let Some(remote) = request.remote {
+        variables.push(("REMOTE_ADDR".to_string(), remote.to_string()));
+    }
+    if !request.body.is_empty() || request.header("Content-Length").is_some() {
+        variables.push(("CONTENT_LENGTH".to_string(), request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        variables.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+
+    for (name, value) in &request.headers {
+        // Credentials are not passed on, and a `Proxy` header would become
+        // HTTP_PROXY, which many tools take as their proxy ("httpoxy").
+        if ["Content-Length", "Content-Type", "Proxy", "Authorization"]
+            .iter()
+            .any(|skipped| name.eq_ignore_ascii_case(skipped))
+        {
+            continue;
+        }
+        let variable = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match variables.iter_mut().find(|(existing, _)| *existing == variable) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => variables.push((variable, value.clone())),
+        }
+    }
+    variables
+}
+
+/// Feeds `body` to the child and collects its stdout, killing it if it is
+/// not done within `timeout`.
+fn collect(mut child: Child, body: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+
+    // Writing from another thread, so that a script that answers before it
+    // reads everything cannot deadlock with us.
+    let mut stdin = child.stdin.take().unwrap();
+    let body = body.to_vec();
+    thread::spawn(move || {
+        // A script is free to ignore its input.
+        let _ = stdin.write_all(&body);
+    });
+    let stderr = child.stderr.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            match line {
+                Ok(line) => warn!("CGI: {}", line),
+                Err(_) => break,
+            }
+        }
+    });
+    let stdout = child.stdout.take().unwrap();
+    let (sender, output) = mpsc::channel();
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let result = stdout.take(MAX_OUTPUT + 1).read_to_end(&mut bytes).map(|_| bytes);
+        let _ = sender.send(result);
+    });
+
+    let output = match output.recv_timeout(timeout) {
+        Ok(output) => output,
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+        }
+    };
+    // The reader gave up at the limit, but the script may well carry on:
+    // stop it now rather than at the timeout.
+    if output.as_ref().is_ok_and(|output| output.len() as u64 > MAX_OUTPUT) {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(io::Error::other("too much output"));
+    }
+    // The output is complete, but the script may still be running.
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let output = output?;
+    if !status.success() {
+        return Err(io::Error::other(format!("exited with {}", status)));
+    }
+    Ok(output)
+}
+
+/// Turns the script's output into a response: CGI headers, where `Status`
+/// sets the status and a lone `Location` redirects, then the body.
+fn parse_output(output: &[u8]) -> io::Result<Response> {
+    let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, reason.to_string());
+    let mut response = Response::new(200);
+    let mut status = None;
+    let mut rest = output;
+    loop {
+        let end = rest
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .ok_or_else(|| invalid("no blank line after the headers"))?;
+        let line = &rest[..end];
+        rest = &rest[end + 1..];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        let line = ::std::str::from_utf8(line).map_err(|_| invalid("headers are not valid UTF-8"))?;
+        let colon = line.find(':').ok_or_else(|| invalid("header without a colon"))?;
+        let (name, value) = (line[..colon].trim(), line[colon + 1..].trim());
+        if name.is_empty() || name.contains(' ') {
+            return Err(invalid("invalid header name"));
+        }
+        if name.eq_ignore_ascii_case("Status") {
+            // Three digits, then the reason phrase.
+            let code = value.split(' ').next().unwrap();
+            if code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_digit()) || !("100"..="599").contains(&code) {
+                return Err(invalid("invalid Status"));
+            }
+            status = Some(code.parse().unwrap());
+        } else if !["Content-Length", "Transfer-Encoding", "Connection"]
+            .iter()
+            .any(|framing| name.eq_ignore_ascii_case(framing))
+        {
+            response.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    if response.header("Content-Type").is_none() && response.header("Location").is_none() {
+        return Err(invalid("neither Content-Type nor Location"));
+    }
+    response.status = status.unwrap_or(if response.header("Location").is_some() { 302 } else { 200 });
+    Ok(response.with_body(rest))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use testing;
+
+    /// A fresh directory holding the shell scripts `scripts`.
+    fn scripts(scripts: &[(&str, &str)]) -> PathBuf {
+        let directory = testing::temp_dir("cgi");
+        for &(name, body) in scripts {
+            let path = directory.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        directory
+    }
+
+    fn text(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn runs_scripts_with_the_cgi_environment() {
+        let directory = scripts(&[(
+            "env.sh",
+            "printf 'Content-Type: text/plain\\r\\nX-Script: env\\r\\n\\r\\n'\n\
+             echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING\"\n\
+             echo \"$SERVER_NAME:$SERVER_PORT $REMOTE_ADDR $GATEWAY_INTERFACE\"\n\
+             echo \"$CONTENT_LENGTH $CONTENT_TYPE $HTTP_X_BOOK [$HTTP_PROXY] [$HOME]\"\n\
+             cat\n",
+        )]);
+        let cgi = Cgi::new(&directory).unwrap();
+        let mut post = Request::test("POST", "/cgi-bin/env.sh/shelf%201/")
+            .with_header("Host", "books.example:8080")
+            .with_header("Content-Type", "text/plain")
+            .with_header("X-Book", "Dune")
+            .with_header("x-book", "Emma")
+            .with_header("Proxy", "http://evil.example")
+            .with_body("the body")
+            .with_remote("10.0.0.7")
+            .with_local("127.0.0.1:7878");
+        post.query = Some("sort=title".to_string());
+
+        let response = cgi.handle(&post).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("text/plain"), response.header("Content-Type"));
+        assert_eq!(Some("env"), response.header("X-Script"));
+        assert_eq!(
+            "POST /cgi-bin/env.sh /shelf 1/ sort=title\n\
+             books.example:7878 10.0.0.7 CGI/1.1\n\
+             8 text/plain Dune, Emma [] []\n\
+             the body",
+            text(response)
+        );
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn follows_status_and_location_headers() {
+        let directory = scripts(&[
+            ("missing.sh", "printf 'Status: 404 Not Found\\nContent-Type: text/plain\\n\\ngone'"),
+            ("moved.sh", "printf 'Location: /books/\\n\\n'"),
+            ("framed.sh", "printf 'Content-Type: text/plain\\nContent-Length: 99\\n\\nok'"),
+        ]);
+        let cgi = Cgi::new(&directory).unwrap();
+
+        let response = cgi.handle(&Request::test("GET", "/cgi-bin/missing.sh")).unwrap();
+        assert_eq!(404, response.status);
+        assert_eq!("gone", text(response));
+
+        let response = cgi.handle(&Request::test("GET", "/cgi-bin/moved.sh")).unwrap();
+        assert_eq!(302, response.status);
+        assert_eq!(Some("/books/"), response.header("Location"));
+
+        let response = cgi.handle(&Request::test("GET", "/cgi-bin/framed.sh")).unwrap();
+        assert_eq!(None, response.header("Content-Length"));
+        assert_eq!("ok", text(response));
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn answers_500_for_broken_scripts() {
+        let directory = scripts(&[
+            ("fails.sh", "printf 'Content-Type: text/plain\\n\\nhalf'\necho oops >&2\nexit 3"),
+            ("headless.sh", "echo just a body"),
+            ("untyped.sh", "printf 'X-Thing: 1\\n\\nbody'"),
+            ("bad-status.sh", "printf 'Status: ok\\nContent-Type: text/plain\\n\\n'"),
+        ]);
+        let cgi = Cgi::new(&directory).unwrap();
+
+        for name in &["fails.sh", "headless.sh", "untyped.sh", "bad-status.sh"] {
+            let response = cgi.handle(&Request::test("GET", &format!("/cgi-bin/{}", name))).unwrap();
+            assert_eq!(500, response.status, "{}", name);
+        }
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn kills_scripts_that_run_too_long() {
+        let directory = scripts(&[("slow.sh", "exec sleep 10")]);
+        let cgi = Cgi::new(&directory).unwrap().timeout(Duration::from_millis(200));
+
+        let started = Instant::now();
+        let response = cgi.handle(&Request::test("GET", "/cgi-bin/slow.sh")).unwrap();
+        assert_eq!(500, response.status);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn kills_scripts_with_too_much_output() {
+        // Carries on after its output is cut off, as a script that ignores
+        // write errors would.
+        let directory = scripts(&[(
+            "chatty.sh",
+            "printf 'Content-Type: text/plain\\n\\n'\nhead -c 17000000 /dev/zero\nexec sleep 10",
+        )]);
+        let cgi = Cgi::new(&directory).unwrap().timeout(Duration::from_secs(30));
+
+        let started = Instant::now();
+        let response = cgi.handle(&Request::test("GET", "/cgi-bin/chatty.sh")).unwrap();
+        assert_eq!(500, response.status);
+        assert!(started.elapsed() < Duration::from_secs(10), "{:?}", started.elapsed());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn only_runs_executables_in_the_directory() {
+        let directory = scripts(&[("ok.sh", "printf 'Content-Type: text/plain\\n\\nok'")]);
+        fs::write(directory.join("data.txt"), "not a script").unwrap();
+        fs::create_dir(directory.join("sub")).unwrap();
+        let cgi = Cgi::new(&directory).unwrap();
+        let status = |path: &str| cgi.handle(&Request::test("GET", path)).map(|response| response.status);
+
+        assert_eq!(Some(200), status("/cgi-bin/ok.sh"));
+        assert_eq!(Some(403), status("/cgi-bin/data.txt"));
+        assert_eq!(Some(404), status("/cgi-bin/missing.sh"));
+        assert_eq!(Some(404), status("/cgi-bin/sub"));
+        assert_eq!(Some(404), status("/cgi-bin/..%2fetc"));
+        assert_eq!(Some(404), status("/cgi-bin/"));
+        assert_eq!(None, status("/cgi-bin"));
+        assert_eq!(None, status("/cgi-binary/ok.sh"));
+        assert_eq!(None, status("/ok.sh"));
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
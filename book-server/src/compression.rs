@@ -0,0 +1,352 @@
+//! Content negotiation by `Accept-Encoding`, and gzip or deflate
+//! compression of responses on the fly.
+
+use std::io::Write;
+
+use flate2::read::{GzEncoder, ZlibEncoder};
+use flate2::write;
+
+use request::Request;
+use response::{Body, Response};
+
+/// Bodies smaller than this gain too little to be worth compressing.
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    /// HTTP's `deflate` is the zlib format, not a raw deflate stream.
+    Deflate,
+}
+
+impl Encoding {
+    /// The name used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// The encoding the client prefers, or `None` for the body as it is. Ties
+/// go to gzip.
+pub fn negotiate(request: &Request) -> Option<Encoding> {
+    let gzip = quality(request, "gzip");
+    let deflate = quality(request, "deflate");
+    if gzip == 0.0 && deflate == 0.0 {
+        None
+    } else if gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else {
+        Some(Encoding::Deflate)
+    }
+}
+
+/// Whether the client takes `coding` at all.
+pub(crate) fn accepts(request: &Request, coding: &str) -> bool {
+    quality(request, coding) > 0.0
+}
+
+/// The `q` the client gave `coding`, directly or through `*`. No header,
+/// like an unlisted coding, counts as 0: compressing for clients that did
+/// not ask is asking for trouble.
+fn quality(request: &Request, coding: &str) -> f32 {
+    let header = match request.header("Accept-Encoding") {
+        Some(header) => header,
+        None => return 0.0,
+    };
+    let mut wildcard = None;
+    for item in header.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap().trim();
+        let q = parts
+            .filter_map(|parameter| {
+                let parameter = parameter.trim();
+                if parameter.len() > 2 && parameter[..2].eq_ignore_ascii_case("q=") {
+                    parameter[2..].parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&q) {
+            continue;
+        }
+        // `x-gzip` is the same as `gzip`, per RFC 7230.
+        if name.eq_ignore_ascii_case(coding) || (coding == "gzip" && name.eq_ignore_ascii_case("x-gzip")) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+/// Whether a body of `content_type` shrinks when compressed: text, and the
+/// usual structured formats. Images, archives and the like are compressed
+/// already.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap().trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime.as_str(),
+            "application/javascript" | "application/json" | "application/xml" | "application/wasm" | "image/x-icon" | "image/bmp"
+        )
+}
+
+/// Compresses responses for clients that accept it.
+///
+/// Bodies in memory are compressed whole and keep a `Content-Length`;
+/// streamed ones are compressed as they are sent, and so go out chunked.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Bodies known to be smaller are sent as they are. Streams of unknown
+    /// length are always compressed.
+    pub min_size: u64,
+    /// From 0, none, to 9, the smallest output.
+    pub level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            min_size: DEFAULT_MIN_SIZE,
+            level: 6,
+        }
+    }
+}
+
+impl Compression {
+    /// `response` to `request`, compressed if both allow it. A 304 gets the
+    /// weak `ETag` of the compressed response it revalidates.
+    pub fn apply(&self, request: &Request, mut response: Response) -> Response {
+        if response.status == 304 {
+            revalidate(request, &mut response);
+            return response;
+        }
+        let eligible = response.status >= 200
+            && response.status < 300
+            && response.status != 204
+            && response.header("Content-Encoding").is_none()
+            && response.header("Content-Type").is_some_and(is_compressible);
+        if !eligible {
+            return response;
+        }
+        // Caches must not hand a compressed body to a client that cannot
+        // take it, or the other way around.
+        add_vary(&mut response);
+        if response.body.len().is_some_and(|length| length < self.min_size) {
+            return response;
+        }
+        let encoding = match negotiate(request) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+
+        let level = flate2::Compression::new(self.level);
+        let body = match response.body {
+            Body::Bytes(bytes) => Body::Bytes(compress_bytes(&bytes, encoding, level)),
+            Body::Stream { reader, .. } => Body::Stream {
+                reader: match encoding {
+                    Encoding::Gzip => Box::new(GzEncoder::new(reader, level)),
+                    Encoding::Deflate => Box::new(ZlibEncoder::new(reader, level)),
+                },
+                length: None,
+            },
+        };
+        response.body = body;
+        // The compressed body is a different representation; like nginx,
+        // keep the tag but only as a weak one.
+        weaken_etag(&mut response);
+        response.with_header("Content-Encoding", encoding.name())
+    }
+}
+
+/// A 304 stands for the 200 the client has, and must carry the same tag.
+/// A client holds only the weak form of a strong tag if its body came
+/// compressed, so such a 304 gets the weak tag, and the `Vary`, back.
+fn revalidate(request: &Request, response: &mut Response) {
+    let (strong, weak) = match response.header("ETag") {
+        Some(etag) if !etag.starts_with("W/") => (etag.to_string(), format!("W/{}", etag)),
+        _ => return,
+    };
+    let listed = |tag: &str| {
+        request
+            .header("If-None-Match")
+            .is_some_and(|tags| tags.split(',').any(|listed| listed.trim() == tag))
+    };
+    if listed(&weak) && !listed(&strong) {
+        weaken_etag(response);
+        add_vary(response);
+    }
+}
+
+fn weaken_etag(response: &mut Response) {
+    for (name, value) in &mut response.headers {
+        if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+            *value = format!("W/{}", value);
+        }
+    }
+}
+
+fn compress_bytes(bytes: &[u8], encoding: Encoding, level: flate2::Compression) -> Vec<u8> {
+    // Writing to a Vec cannot fail.
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = write::GzEncoder::new(Vec::new(), level);
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+        Encoding::Deflate => {
+            let mut encoder = write::ZlibEncoder::new(Vec::new(), level);
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+    }
+}
+
+/// Adds `Accept-Encoding` to the `Vary` header, unless it is there.
+pub(crate) fn add_vary(response: &mut Response) {
+    for (name, value) in &mut response.headers {
+        if name.eq_ignore_ascii_case("Vary") {
+            let listed = value
+                .split(',')
+                .any(|field| field.trim() == "*" || field.trim().eq_ignore_ascii_case("Accept-Encoding"));
+            if !listed {
+                value.push_str(", Accept-Encoding");
+            }
+            return;
+        }
+    }
+    response.headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::{Cursor, Read};
+
+    fn request(accept_encoding: Option<&str>) -> Request {
+        let request = Request::test("GET", "/");
+        match accept_encoding {
+            Some(value) => request.with_header("Accept-Encoding", value),
+            None => request,
+        }
+    }
+
+    fn gunzip(bytes: &[u8]) -> String {
+        let mut text = String::new();
+        GzDecoder::new(bytes).read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        let negotiate = |header| negotiate(&request(header));
+
+        assert_eq!(None, negotiate(None));
+        assert_eq!(None, negotiate(Some("")));
+        assert_eq!(None, negotiate(Some("br, identity")));
+        assert_eq!(Some(Encoding::Gzip), negotiate(Some("gzip, deflate, br")));
+        assert_eq!(Some(Encoding::Gzip), negotiate(Some("x-gzip")));
+        assert_eq!(Some(Encoding::Deflate), negotiate(Some("deflate")));
+        assert_eq!(Some(Encoding::Deflate), negotiate(Some("gzip;q=0.5, deflate")));
+        assert_eq!(Some(Encoding::Deflate), negotiate(Some("gzip;q=0, *")));
+        assert_eq!(Some(Encoding::Gzip), negotiate(Some("*;q=0.1")));
+        assert_eq!(None, negotiate(Some("*;q=0")));
+        assert_eq!(None, negotiate(Some("GZIP; Q=0, deflate;q=0.000")));
+        // A broken weight is ignored along with its coding.
+        assert_eq!(Some(Encoding::Deflate), negotiate(Some("gzip;q=2, deflate;q=0.2")));
+    }
+
+    #[test]
+    fn knows_what_is_worth_compressing() {
+        for compressible in &["text/html; charset=utf-8", "application/json", "image/svg+xml", "TEXT/CSS"] {
+            assert!(is_compressible(compressible), "{}", compressible);
+        }
+        for compressed in &["image/png", "application/gzip", "video/mp4", "application/octet-stream"] {
+            assert!(!is_compressible(compressed), "{}", compressed);
+        }
+    }
+
+    #[test]
+    fn compresses_bodies_in_memory() {
+        let text = "It is a truth universally acknowledged. ".repeat(100);
+        let response = Response::text(200, &text).with_header("ETag", "\"abc\"");
+        let response = Compression::default().apply(&request(Some("gzip")), response);
+
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_eq!(Some("W/\"abc\""), response.header("ETag"));
+        let length = response.body.len().unwrap();
+        assert!(length < text.len() as u64 / 10, "{}", length);
+        assert_eq!(text, gunzip(&response.body.into_bytes().unwrap()));
+
+        let response = Compression::default().apply(&request(Some("deflate")), Response::text(200, &text));
+        let mut inflated = String::new();
+        ZlibDecoder::new(&response.body.into_bytes().unwrap()[..])
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(text, inflated);
+    }
+
+    #[test]
+    fn compresses_streams_as_they_go() {
+        let text = "chapter ".repeat(10_000);
+        let response = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_stream(Cursor::new(text.clone().into_bytes()), Some(text.len() as u64));
+        let response = Compression::default().apply(&request(Some("gzip")), response);
+
+        assert_eq!(None, response.body.len());
+        let mut raw = Vec::new();
+        response.write_to(&mut raw, true).unwrap();
+        let raw = String::from_utf8_lossy(&raw);
+        assert!(raw.contains("Content-Encoding: gzip\r\n"), "{}", raw);
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"), "{}", raw);
+        assert!(!raw.contains("Content-Length"), "{}", raw);
+    }
+
+    #[test]
+    fn leaves_some_responses_alone() {
+        let compression = Compression::default();
+        let accepting = request(Some("gzip"));
+        let long = "a".repeat(2000);
+
+        // Too small, though it may vary.
+        let response = compression.apply(&accepting, Response::text(200, "short"));
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+
+        let response = compression.apply(&request(None), Response::text(200, &long));
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(Some(2000), response.body.len());
+
+        let png = Response::new(200).with_header("Content-Type", "image/png").with_body(long.clone());
+        let response = compression.apply(&accepting, png);
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(None, response.header("Vary"));
+
+        let precompressed = Response::text(200, &long).with_header("Content-Encoding", "br");
+        assert_eq!(Some(2000), compression.apply(&accepting, precompressed).body.len());
+
+        let not_found = Response::text(404, &long);
+        assert_eq!(None, compression.apply(&accepting, not_found).header("Content-Encoding"));
+    }
+
+    #[test]
+    fn extends_an_existing_vary() {
+        let mut response = Response::new(200).with_header("vary", "Cookie");
+        add_vary(&mut response);
+        add_vary(&mut response);
+        assert_eq!(Some("Cookie, Accept-Encoding"), response.header("Vary"));
+        assert_eq!(1, response.headers.len());
+    }
+}
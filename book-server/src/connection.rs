@@ -0,0 +1,501 @@
+//! Persistent connections: many requests, one after the other, over one
+//! `TcpStream`.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use access_log::{AccessLog, Entry};
+use compression::Compression;
+use proxy::Proxy;
+use request::{read_body, read_head, Limits, ParseError, Request};
+use response::Response;
+
+/// How long a connection may sit between requests before it is closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may take to send the request line and headers.
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long writing a response may stall before the connection is dropped.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How connections are served.
+#[derive(Clone)]
+pub struct Settings {
+    pub limits: Limits,
+    /// Also bounds each read of a request, but not a whole request: a
+    /// client that keeps trickling bytes is only stopped by `header_timeout`.
+    pub idle_timeout: Duration,
+    /// Bounds the request line and headers together, from the first byte.
+    pub header_timeout: Duration,
+    pub write_timeout: Duration,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Off unless set.
+    pub compression: Option<Compression>,
+    /// Takes the requests under its prefixes before the handler, streaming
+    /// their bodies upstream. None unless set.
+    pub proxy: Option<Arc<Proxy>>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            limits: Limits::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            access_log: None,
+            compression: None,
+            proxy: None,
+        }
+    }
+}
+
+/// Answers requests on `stream` with `handler` until the client asks to
+/// close, goes quiet for the idle timeout, or sends something unparseable.
+///
+/// Pipelined requests are answered in order, since each is read only after
+/// the previous response went out.
+pub fn serve_connection<F>(stream: TcpStream, settings: &Settings, mut handler: F) -> io::Result<()>
+where
+    F: FnMut(&Request) -> Response,
+{
+    stream.set_read_timeout(Some(settings.idle_timeout))?;
+    stream.set_write_timeout(Some(settings.write_timeout))?;
+    let remote = stream.peer_addr().ok().map(|address| address.ip());
+    let local = stream.local_addr().ok();
+    let mut reader = BufReader::new(Timed {
+        stream: &stream,
+        idle_timeout: settings.idle_timeout,
+        deadline: None,
+    });
+    let mut writer = &stream;
+
+    loop {
+        // Wait for the first byte, so that the clock does not count the time
+        // the connection sat idle. An idle timeout ends the connection.
+        if reader.fill_buf().is_err() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        // Timeouts in the middle of a request end up in errors too, as I/O
+        // errors without a status.
+        reader.get_mut().deadline = Some(started + settings.header_timeout);
+        let head = read_head(&mut reader, &settings.limits);
+        reader.get_mut().deadline = None;
+        stream.set_read_timeout(Some(settings.idle_timeout))?;
+        let mut head = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(()),
+            Err(error) => return reject(&error, writer, settings, remote, started),
+        };
+        head.request.remote = remote;
+        head.request.local = local;
+
+        let proxied = settings
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.stream(&head.request, head.framing, &mut reader));
+        let (request, mut response, body_read) = match proxied {
+            Some((response, body_read)) => (head.request, response, body_read),
+            None => {
+                if let Err(error) = read_body(&mut reader, &mut head, &settings.limits) {
+                    return reject(&error, writer, settings, remote, started);
+                }
+                let response = handler(&head.request);
+                (head.request, response, true)
+            }
+        };
+        if let Some(ref compression) = settings.compression {
+            response = compression.apply(&request, response);
+        }
+        // HTTP/1.0 has no chunks, so only the end of the connection can end
+        // a body of unknown length.
+        let unframed = request.version == "HTTP/1.0" && response.body.len().is_none();
+        // What is left of an unread body would be taken for the next request.
+        let keep_alive = wants_keep_alive(&request) && body_read && !unframed && !response.closes_connection();
+        if !keep_alive {
+            if !response.closes_connection() {
+                response = response.with_header("Connection", "close");
+            }
+        } else if request.version == "HTTP/1.0" {
+            response = response.with_header("Connection", "keep-alive");
+        }
+        let status = response.status;
+        let include_body = request.method != "HEAD";
+        let bytes = response.write_to(&mut writer, include_body)?;
+
+        if let Some(ref access_log) = settings.access_log {
+            let request_line = match request.query {
+                Some(ref query) => format!("{} {}?{} {}", request.method, request.path, query, request.version),
+                None => format!("{} {} {}", request.method, request.path, request.version),
+            };
+            access_log.log(&Entry {
+                remote,
+                time: SystemTime::now(),
+                request_line: Some(&request_line),
+                status,
+                bytes,
+                referer: request.header("Referer"),
+                user_agent: request.header("User-Agent"),
+                latency: started.elapsed(),
+            });
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// The connection as requests are read from it: every read waits at most
+/// the idle timeout, and none goes past the deadline, if there is one.
+struct Timed<'a> {
+    stream: &'a TcpStream,
+    idle_timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Read for Timed<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the request took too long"));
+            }
+            self.stream.set_read_timeout(Some(left.min(self.idle_timeout)))?;
+        }
+        self.stream.read(buffer)
+    }
+}
+
+/// Answers a request that could not be read, if the connection is still
+/// usable enough for that, before it is closed.
+fn reject(
+    error: &ParseError,
+    mut writer: &TcpStream,
+    settings: &Settings,
+    remote: Option<IpAddr>,
+    started: Instant,
+) -> io::Result<()> {
+    if let Some((code, _)) = error.status() {
+        let response = Response::text(code, &format!("{}\n", error)).with_header("Connection", "close");
+        let bytes = response.write_to(&mut writer, true)?;
+        if let Some(ref access_log) = settings.access_log {
+            access_log.log(&Entry {
+                remote,
+                time: SystemTime::now(),
+                request_line: None,
+                status: code,
+                bytes,
+                referer: None,
+                user_agent: None,
+                latency: started.elapsed(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// HTTP/1.1 connections persist unless either side says `close`; HTTP/1.0
+/// ones only when the client asks for `keep-alive`.
+fn wants_keep_alive(request: &Request) -> bool {
+    let has_token = |token: &str| {
+        request
+            .header("Connection")
+            .is_some_and(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+    };
+    if request.version == "HTTP/1.0" {
+        has_token("keep-alive")
+    } else {
+        !has_token("close")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, Cursor, Read, Write};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use access_log::LogFormat;
+    use testing;
+
+    /// Serves one connection that echoes the method and path, and returns a
+    /// client socket connected to it.
+    fn connect(idle_timeout: Duration) -> (TcpStream, thread::JoinHandle<usize>) {
+        connect_with(Settings {
+            idle_timeout,
+            ..Settings::default()
+        })
+    }
+
+    fn connect_with(settings: Settings) -> (TcpStream, thread::JoinHandle<usize>) {
+        connect_to(settings, |request| {
+            let body = format!("{} {} {}", request.method, request.path, String::from_utf8_lossy(&request.body));
+            Response::text(200, &body)
+        })
+    }
+
+    /// Serves one connection with `handler`; the server thread returns how
+    /// many requests it handled.
+    fn connect_to<F>(settings: Settings, mut handler: F) -> (TcpStream, thread::JoinHandle<usize>)
+    where
+        F: FnMut(&Request) -> Response + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut handled = 0;
+            serve_connection(stream, &settings, |request| {
+                handled += 1;
+                handler(request)
+            }).unwrap();
+            handled
+        });
+        let client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    /// Reads one response by its Content-Length, returning its head and body.
+    fn read_response<R: BufRead>(reader: &mut R) -> (String, String) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert!(!line.is_empty(), "connection closed after {:?}", head);
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let length = head
+            .lines()
+            .find(|line| line.starts_with("Content-Length: "))
+            .map_or(0, |line| line["Content-Length: ".len()..].parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn assert_closed<R: Read>(reader: &mut R) {
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+    }
+
+    #[test]
+    fn serves_several_requests_over_one_socket() {
+        let (mut client, server) = connect(DEFAULT_IDLE_TIMEOUT);
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        for path in &["/one", "/two", "/three"] {
+            write!(client, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let (head, body) = read_response(&mut reader);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(!head.contains("Connection:"));
+            assert_eq!(format!("GET {} ", path), body);
+        }
+        write!(client, "POST /last HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndone").unwrap();
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!("POST /last done", body);
+
+        assert_closed(&mut reader);
+        assert_eq!(4, server.join().unwrap());
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let (mut client, server) = connect(DEFAULT_IDLE_TIMEOUT);
+        client
+            .write_all(
+                b"GET /a HTTP/1.1\r\n\r\n\
+                  PUT /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nxyz\
+                  HEAD /c HTTP/1.1\r\n\r\n\
+                  GET /d HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        assert_eq!("GET /a ", read_response(&mut reader).1);
+        assert_eq!("PUT /b xyz", read_response(&mut reader).1);
+        // A HEAD response announces a length but has no body to read.
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.contains("Content-Length: 8\r\n"));
+        assert_eq!("GET /d ", read_response(&mut reader).1);
+
+        assert_closed(&mut reader);
+        assert_eq!(4, server.join().unwrap());
+    }
+
+    #[test]
+    fn closes_http_1_0_connections_unless_asked_to_keep_them() {
+        let (mut client, server) = connect(DEFAULT_IDLE_TIMEOUT);
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client.write_all(b"GET /kept HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: keep-alive\r\n"));
+
+        client.write_all(b"GET /closed HTTP/1.0\r\n\r\n").unwrap();
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!("GET /closed ", body);
+
+        assert_closed(&mut reader);
+        assert_eq!(2, server.join().unwrap());
+    }
+
+    #[test]
+    fn streams_bodies_of_unknown_length() {
+        let (mut client, server) = connect_to(Settings::default(), |request| {
+            let text = format!("streamed {}", request.path);
+            Response::new(200).with_stream(Cursor::new(text.into_bytes()), None)
+        });
+        client
+            .write_all(
+                b"GET /chunked HTTP/1.1\r\n\r\n\
+                  GET /unframed HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            )
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).unwrap();
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n11\r\nstreamed /chunked\r\n0\r\n\r\n\
+             HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nstreamed /unframed",
+            raw
+        );
+        assert_eq!(2, server.join().unwrap());
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let (mut client, server) = connect(Duration::from_millis(100));
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        read_response(&mut reader);
+
+        let started = Instant::now();
+        assert_closed(&mut reader);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(1, server.join().unwrap());
+    }
+
+    #[test]
+    fn bounds_the_time_to_send_the_headers() {
+        let (mut client, server) = connect_with(Settings {
+            idle_timeout: Duration::from_millis(500),
+            header_timeout: Duration::from_millis(300),
+            ..Settings::default()
+        });
+        // Each line comes well within the idle timeout, but the headers never
+        // end.
+        thread::spawn(move || {
+            let _ = client.write_all(b"GET / HTTP/1.1\r\n");
+            for _ in 0..40 {
+                thread::sleep(Duration::from_millis(50));
+                if client.write_all(b"X-Trickle: 1\r\n").is_err() {
+                    break;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        assert_eq!(0, server.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn closes_after_a_malformed_request() {
+        let (mut client, server) = connect(DEFAULT_IDLE_TIMEOUT);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\nNONSENSE\r\n\r\nGET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+
+        assert_eq!("GET / ", read_response(&mut reader).1);
+        let (head, body) = read_response(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!("Malformed request: invalid request line\n", body);
+
+        assert_closed(&mut reader);
+        assert_eq!(1, server.join().unwrap());
+    }
+
+    #[test]
+    fn logs_every_request() {
+        let directory = testing::temp_dir("access");
+        let path = directory.join("access.log");
+        let (mut client, server) = connect_with(Settings {
+            access_log: Some(Arc::new(AccessLog::to_file(&path, LogFormat::Combined).unwrap())),
+            ..Settings::default()
+        });
+        client
+            .write_all(
+                b"GET /books?page=2 HTTP/1.1\r\nUser-Agent: test/1.0\r\nReferer: /\r\n\r\n\
+                  HEAD /books HTTP/1.1\r\n\r\n\
+                  NONSENSE\r\n\r\n",
+            )
+            .unwrap();
+        client.read_to_end(&mut Vec::new()).unwrap();
+        server.join().unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(3, lines.len(), "{}", log);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["), "{}", lines[0]);
+        assert!(
+            lines[0].contains("] \"GET /books?page=2 HTTP/1.1\" 200 11 \"/\" \"test/1.0\" "),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[1].contains("] \"HEAD /books HTTP/1.1\" 200 - \"-\" \"-\" "), "{}", lines[1]);
+        assert!(lines[2].contains("] \"-\" 400 "), "{}", lines[2]);
+        for line in lines {
+            let latency = line.rsplit(' ').next().unwrap();
+            assert!(latency.parse::<u64>().is_ok(), "{}", line);
+        }
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn latency_counts_from_the_first_byte() {
+        let directory = testing::temp_dir("latency");
+        let path = directory.join("access.log");
+        let (mut client, server) = connect_with(Settings {
+            access_log: Some(Arc::new(AccessLog::to_file(&path, LogFormat::Common).unwrap())),
+            ..Settings::default()
+        });
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        // Idle before the request: not counted.
+        thread::sleep(Duration::from_millis(300));
+        client.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+        read_response(&mut reader);
+        // Slow to arrive once started: counted.
+        client.write_all(b"GET /slow HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        client.write_all(b"Connection: close\r\n\r\n").unwrap();
+        read_response(&mut reader);
+        server.join().unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let latencies = log
+            .lines()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(latencies[0] < 200_000, "{}", log);
+        assert!(latencies[1] >= 300_000, "{}", log);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,151 @@
+//! HTTP dates, such as `Sun, 06 Nov 1994 08:49:37 GMT`, without pulling in a
+//! date library.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A point in time broken down in UTC. Times before 1970 count as 1970.
+#[derive(Debug, PartialEq)]
+pub struct Civil {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 0 is Sunday.
+    pub weekday: u32,
+}
+
+impl Civil {
+    pub fn from_time(time: SystemTime) -> Civil {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or(0);
+        let days = seconds.div_euclid(86_400);
+        let of_day = seconds.rem_euclid(86_400) as u32;
+
+        // Howard Hinnant's days-to-civil algorithm.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Civil {
+            year,
+            month,
+            day,
+            hour: of_day / 3600,
+            minute: of_day / 60 % 60,
+            second: of_day % 60,
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    pub fn month_name(&self) -> &'static str {
+        MONTHS[self.month as usize - 1]
+    }
+}
+
+/// Days from 1970-01-01 to the given date, the inverse of the above.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let shifted_month = i64::from(if month > 2 { month - 3 } else { month + 9 });
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Formats `time` as an IMF-fixdate, the form HTTP/1.1 senders must use.
+pub fn format_http_date(time: SystemTime) -> String {
+    let civil = Civil::from_time(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[civil.weekday as usize],
+        civil.day,
+        civil.month_name(),
+        civil.year,
+        civil.hour,
+        civil.minute,
+        civil.second
+    )
+}
+
+/// Parses an IMF-fixdate. The obsolete RFC 850 and asctime forms are not
+/// accepted; a date that does not parse is ignored by the caller, as RFC
+/// 7232 asks.
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    let parts = text.split(' ').collect::<Vec<_>>();
+    if parts.len() != 6 || !DAYS.iter().any(|day| parts[0] == format!("{},", day)) || parts[5] != "GMT" {
+        return None;
+    }
+    let day = parse_digits(parts[1], 2)?;
+    let month = MONTHS.iter().position(|month| *month == parts[2])? as u32 + 1;
+    let year = i64::from(parse_digits(parts[3], 4)?);
+    let clock = parts[4].split(':').collect::<Vec<_>>();
+    if clock.len() != 3 {
+        return None;
+    }
+    let hour = parse_digits(clock[0], 2)?;
+    let minute = parse_digits(clock[1], 2)?;
+    let second = parse_digits(clock[2], 2)?;
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 || year < 1970 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+    Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+fn parse_digits(text: &str, width: usize) -> Option<u32> {
+    if text.len() != width || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_parses_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", format_http_date(time));
+        assert_eq!(Some(time), parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT", format_http_date(UNIX_EPOCH));
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!("Tue, 29 Feb 2000 00:00:00 GMT", format_http_date(leap_day));
+        assert_eq!(Some(leap_day), parse_http_date(&format_http_date(leap_day)));
+    }
+
+    #[test]
+    fn ignores_other_date_forms() {
+        for text in &[
+            "",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+        ] {
+            assert_eq!(None, parse_http_date(text), "{}", text);
+        }
+    }
+}
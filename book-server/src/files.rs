@@ -0,0 +1,565 @@
+//! Serving files from a document root.
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use compression::{accepts, add_vary};
+use date::{format_http_date, parse_http_date};
+use request::{percent_decode, Request};
+use response::Response;
+
+pub struct StaticFiles {
+    root: PathBuf,
+    listings: bool,
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    /// Serves the files under `root`, which must be an existing directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<StaticFiles> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "document root is not a directory"));
+        }
+        Ok(StaticFiles {
+            root,
+            listings: false,
+            precompressed: false,
+        })
+    }
+
+    /// Whether directories without an index.html are listed, rather than
+    /// answered with 404. Off by default.
+    pub fn listings(mut self, enabled: bool) -> StaticFiles {
+        self.listings = enabled;
+        self
+    }
+
+    /// Whether a `name.gz` next to `name` is sent instead, to clients that
+    /// accept gzip. Off by default.
+    pub fn precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Response::text(405, "Method not allowed\n").with_header("Allow", "GET, HEAD");
+        }
+        let path = match self.resolve(&request.path) {
+            Ok(path) => path,
+            Err(response) => return response,
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(error) => return self.error(&error),
+        };
+
+        if !metadata.is_dir() {
+            return self.file(request, &path, &metadata);
+        }
+        // Relative links in the index or listing need the trailing slash.
+        if !request.path.ends_with('/') {
+            let location = match request.query {
+                Some(ref query) => format!("{}/?{}", request.path, query),
+                None => format!("{}/", request.path),
+            };
+            return Response::text(301, "Moved permanently\n").with_header("Location", &location);
+        }
+        let index = path.join("index.html");
+        match fs::metadata(&index) {
+            Ok(ref metadata) if metadata.is_file() => self.file(request, &index, metadata),
+            _ if self.listings => self.listing(&request.path, &path),
+            _ => self.not_found(),
+        }
+    }
+
+    /// Maps a request path onto the file system, refusing anything that
+    /// would lead outside the root.
+    fn resolve(&self, request_path: &str) -> Result<PathBuf, Response> {
+        let decoded = percent_decode(request_path).ok_or_else(|| Response::text(400, "Invalid path\n"))?;
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(self.not_found()),
+                _ if segment.contains('\\') || segment.contains('\0') => return Err(self.not_found()),
+                _ => path.push(segment),
+            }
+        }
+        match is_within(&self.root, &path) {
+            Ok(true) => Ok(path),
+            Ok(false) => Err(self.not_found()),
+            Err(error) => Err(self.error(&error)),
+        }
+    }
+
+    fn file(&self, request: &Request, path: &Path, metadata: &Metadata) -> Response {
+        let sibling = if self.precompressed { self.gzipped(path) } else { None };
+        let mut response = Response::new(200);
+        if sibling.is_some() {
+            add_vary(&mut response);
+        }
+        // The tags below then describe the gzipped file, a representation
+        // of its own.
+        let (served, metadata, encoding) = match sibling {
+            Some((ref gzipped, ref gzipped_metadata)) if accepts(request, "gzip") => {
+                (gzipped.as_path(), gzipped_metadata, Some("gzip"))
+            }
+            _ => (path, metadata, None),
+        };
+
+        let modified = metadata.modified().ok();
+        let etag = modified.map(|modified| {
+            let since = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            format!("\"{:x}-{:x}.{:x}\"", metadata.len(), since.as_secs(), since.subsec_nanos())
+        });
+        let last_modified = modified.map(format_http_date);
+
+        if let Some(ref etag) = etag {
+            response = response.with_header("ETag", etag);
+        }
+        if let Some(ref last_modified) = last_modified {
+            response = response.with_header("Last-Modified", last_modified);
+        }
+        if is_not_modified(request, etag.as_deref(), last_modified.as_deref()) {
+            response.status = 304;
+            return response;
+        }
+
+        // Streamed, so that large files do not have to fit in memory.
+        let opened = File::open(served).and_then(|file| file.metadata().map(|metadata| (file, metadata.len())));
+        let (file, length) = match opened {
+            Ok(opened) => opened,
+            Err(error) => return self.error(&error),
+        };
+        response = response.with_header("Content-Type", content_type(path));
+        if let Some(encoding) = encoding {
+            response = response.with_header("Content-Encoding", encoding);
+        }
+        response.with_stream(file, Some(length))
+    }
+
+    /// The `.gz` sibling of the file at `path`, if there is one inside the
+    /// root.
+    fn gzipped(&self, path: &Path) -> Option<(PathBuf, Metadata)> {
+        let mut name = path.file_name()?.to_os_string();
+        name.push(".gz");
+        let gzipped = path.with_file_name(name);
+        let metadata = fs::metadata(&gzipped).ok().filter(Metadata::is_file)?;
+        match is_within(&self.root, &gzipped) {
+            Ok(true) => Some((gzipped, metadata)),
+            _ => None,
+        }
+    }
+
+    fn listing(&self, request_path: &str, directory: &Path) -> Response {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(error) => return self.error(&error),
+        };
+        let mut names = entries
+            .filter_map(Result::ok)
+            .map(|entry| {
+                let mut name = entry.file_name().to_string_lossy().into_owned();
+                if entry.path().is_dir() {
+                    name.push('/');
+                }
+                name
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+
+        let title = format!("Index of {}", escape_html(&percent_decode(request_path).unwrap_or_default()));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n  <head>\n    <meta charset=\"utf-8\">\n    <title>{0}</title>\n  </head>\n  <body>\n    <h1>{0}</h1>\n    <ul>\n",
+            title
+        );
+        if request_path != "/" {
+            html.push_str("      <li><a href=\"../\">../</a></li>\n");
+        }
+        for name in &names {
+            html.push_str(&format!(
+                "      <li><a href=\"{}\">{}</a></li>\n",
+                percent_encode(name),
+                escape_html(name)
+            ));
+        }
+        html.push_str("    </ul>\n  </body>\n</html>\n");
+
+        Response::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(html)
+    }
+
+    /// 404, with the root's 404.html as the page if there is one.
+    fn not_found(&self) -> Response {
+        match fs::read(self.root.join("404.html")) {
+            Ok(page) => Response::new(404)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(page),
+            Err(_) => Response::text(404, "Not found\n"),
+        }
+    }
+
+    fn error(&self, error: &io::Error) -> Response {
+        match error.kind() {
+            // A path through a regular file fails with NotADirectory.
+            ErrorKind::NotFound | ErrorKind::NotADirectory => self.not_found(),
+            ErrorKind::PermissionDenied => Response::text(403, "Forbidden\n"),
+            _ => Response::text(500, "Internal server error\n"),
+        }
+    }
+}
+
+/// Whether `path` is inside `root`, which must be canonical. Symbolic links
+/// are followed, since they may point anywhere.
+pub(crate) fn is_within(root: &Path, path: &Path) -> io::Result<bool> {
+    Ok(fs::canonicalize(path)?.starts_with(root))
+}
+
+/// Whether the client's cached copy is still good. If-None-Match wins over
+/// If-Modified-Since when both are sent, per RFC 7232.
+fn is_not_modified(request: &Request, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        return etag.is_some_and(|etag| {
+            if_none_match
+                .split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        });
+    }
+    match (request.header("If-Modified-Since").and_then(parse_http_date), last_modified) {
+        // Compare at the second granularity of the header.
+        (Some(since), Some(last_modified)) => parse_http_date(last_modified).is_some_and(|modified| modified <= since),
+        _ => false,
+    }
+}
+
+/// The MIME type for a file, by its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") | Some("md") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use compression::Compression;
+    use response::Body;
+    use testing;
+
+    /// A fresh document root:
+    ///
+    /// ```text
+    /// index.html  logo.png  notes.txt  docs/guide.md  empty/
+    /// ```
+    fn document_root() -> PathBuf {
+        let root = testing::temp_dir("files");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("index.html"), "<h1>Books</h1>").unwrap();
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0xff, b'\r', b'\n']).unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        fs::write(root.join("docs/guide.md"), "# Guide").unwrap();
+        root
+    }
+
+    fn get(path: &str) -> Request {
+        Request::test("GET", path)
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        response.body.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn serves_files_with_their_type() {
+        let files = StaticFiles::new(document_root()).unwrap();
+
+        let response = files.serve(&get("/notes.txt"));
+        assert_eq!(200, response.status);
+        assert_eq!(Some("text/plain; charset=utf-8"), response.header("Content-Type"));
+        assert_eq!(b"notes", &body(response)[..]);
+
+        let response = files.serve(&get("/logo.png"));
+        assert_eq!(Some("image/png"), response.header("Content-Type"));
+        assert_eq!(vec![0x89, b'P', b'N', b'G', 0, 0xff, b'\r', b'\n'], body(response));
+
+        let response = files.serve(&get("/docs/guide.md"));
+        assert_eq!(b"# Guide", &body(response)[..]);
+        assert_eq!(404, files.serve(&get("/missing.txt")).status);
+        assert_eq!(404, files.serve(&get("/notes.txt/more")).status);
+    }
+
+    #[test]
+    fn streams_files_with_their_length() {
+        let root = document_root();
+        let contents = (0..4 * 1024 * 1024).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+        fs::write(root.join("large.bin"), &contents).unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+
+        let response = files.serve(&get("/large.bin"));
+        match response.body {
+            Body::Stream { length, .. } => assert_eq!(Some(contents.len() as u64), length),
+            ref other => panic!("{:?}", other),
+        }
+        assert!(contents == body(response));
+    }
+
+    #[test]
+    fn decodes_escaped_paths() {
+        let root = document_root();
+        fs::write(root.join("two words.txt"), "spaced").unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+
+        assert_eq!(b"spaced", &body(files.serve(&get("/two%20words.txt")))[..]);
+        assert_eq!(b"notes", &body(files.serve(&get("/.//notes.txt")))[..]);
+        assert_eq!(400, files.serve(&get("/notes%2")).status);
+        assert_eq!(400, files.serve(&get("/%ff")).status);
+    }
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        let root = document_root();
+        let files = StaticFiles::new(root.join("docs")).unwrap();
+
+        for path in &[
+            "/../notes.txt",
+            "/%2e%2e/notes.txt",
+            "/..%2fnotes.txt",
+            "/guide.md/../../notes.txt",
+            "/..\\notes.txt",
+            "/%00",
+        ] {
+            let response = files.serve(&get(path));
+            assert_eq!(404, response.status, "{}", path);
+            assert_ne!(b"notes", &body(response)[..], "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        let root = document_root();
+        ::std::os::unix::fs::symlink(root.join("notes.txt"), root.join("docs/escape.txt")).unwrap();
+        let files = StaticFiles::new(root.join("docs")).unwrap();
+
+        assert_eq!(404, files.serve(&get("/escape.txt")).status);
+    }
+
+    #[test]
+    fn serves_directory_indexes() {
+        let files = StaticFiles::new(document_root()).unwrap();
+
+        let response = files.serve(&get("/"));
+        assert_eq!(200, response.status);
+        assert_eq!(Some("text/html; charset=utf-8"), response.header("Content-Type"));
+        assert_eq!(b"<h1>Books</h1>", &body(response)[..]);
+
+        let response = files.serve(&get("/docs"));
+        assert_eq!(301, response.status);
+        assert_eq!(Some("/docs/"), response.header("Location"));
+        assert_eq!(404, files.serve(&get("/docs/")).status);
+    }
+
+    #[test]
+    fn lists_directories_when_enabled() {
+        let root = document_root();
+        fs::write(root.join("empty/<b>&.txt"), "").unwrap();
+        let files = StaticFiles::new(&root).unwrap().listings(true);
+
+        let response = files.serve(&get("/docs/"));
+        assert_eq!(200, response.status);
+        let html = String::from_utf8(body(response)).unwrap();
+        assert!(html.contains("<title>Index of /docs/</title>"));
+        assert!(html.contains("<a href=\"../\">../</a>"));
+        assert!(html.contains("<a href=\"guide.md\">guide.md</a>"));
+
+        let html = String::from_utf8(body(files.serve(&get("/empty/")))).unwrap();
+        assert!(html.contains("<a href=\"%3Cb%3E%26.txt\">&lt;b&gt;&amp;.txt</a>"));
+
+        // An index.html still wins.
+        assert_eq!(b"<h1>Books</h1>", &body(files.serve(&get("/")))[..]);
+    }
+
+    #[test]
+    fn uses_the_roots_404_page() {
+        let root = document_root();
+        fs::write(root.join("404.html"), "<h1>Lost</h1>").unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+
+        let response = files.serve(&get("/nowhere"));
+        assert_eq!(404, response.status);
+        assert_eq!(b"<h1>Lost</h1>", &body(response)[..]);
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let files = StaticFiles::new(document_root()).unwrap();
+        let response = files.serve(&get("/notes.txt"));
+        let etag = response.header("ETag").unwrap().to_string();
+        let last_modified = response.header("Last-Modified").unwrap().to_string();
+
+        let revalidate = |name: &str, value: &str| files.serve(&get("/notes.txt").with_header(name, value));
+
+        let response = revalidate("If-None-Match", &etag);
+        assert_eq!(304, response.status);
+        assert!(response.body.is_empty());
+        assert_eq!(Some(etag.as_str()), response.header("ETag"));
+
+        assert_eq!(304, revalidate("If-None-Match", &format!("\"other\", W/{}", etag)).status);
+        assert_eq!(304, revalidate("If-None-Match", "*").status);
+        assert_eq!(200, revalidate("If-None-Match", "\"other\"").status);
+
+        assert_eq!(304, revalidate("If-Modified-Since", &last_modified).status);
+        assert_eq!(304, revalidate("If-Modified-Since", "Fri, 31 Dec 9999 23:59:59 GMT").status);
+        assert_eq!(200, revalidate("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT").status);
+        assert_eq!(200, revalidate("If-Modified-Since", "yesterday").status);
+
+        // A changed ETag wins over a matching date.
+        let response = files.serve(
+            &get("/notes.txt")
+                .with_header("If-None-Match", "\"other\"")
+                .with_header("If-Modified-Since", &last_modified),
+        );
+        assert_eq!(200, response.status);
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let root = document_root();
+        fs::write(root.join("notes.txt.gz"), "pretend gzip").unwrap();
+        let gzip = get("/notes.txt").with_header("Accept-Encoding", "gzip, deflate");
+
+        // Only when asked to.
+        let files = StaticFiles::new(&root).unwrap();
+        let response = files.serve(&gzip);
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(None, response.header("Vary"));
+
+        let files = files.precompressed(true);
+        let response = files.serve(&gzip);
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        assert_eq!(Some("text/plain; charset=utf-8"), response.header("Content-Type"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_eq!(Some(12), response.body.len());
+        let gzipped_etag = response.header("ETag").unwrap().to_string();
+        assert_eq!(b"pretend gzip", &body(response)[..]);
+
+        let response = files.serve(&get("/notes.txt").with_header("Accept-Encoding", "gzip;q=0"));
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_ne!(Some(gzipped_etag.as_str()), response.header("ETag"));
+        assert_eq!(b"notes", &body(response)[..]);
+
+        let revalidated = files.serve(
+            &get("/notes.txt")
+                .with_header("Accept-Encoding", "gzip")
+                .with_header("If-None-Match", &gzipped_etag),
+        );
+        assert_eq!(304, revalidated.status);
+        assert_eq!(Some("Accept-Encoding"), revalidated.header("Vary"));
+
+        // Files without a sibling are left as they are.
+        let response = files.serve(&get("/docs/guide.md").with_header("Accept-Encoding", "gzip, deflate"));
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(None, response.header("Vary"));
+    }
+
+    #[test]
+    fn revalidates_compressed_responses() {
+        let root = document_root();
+        fs::write(root.join("long.txt"), "Call me Ishmael. ".repeat(100)).unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+        let compression = Compression::default();
+        let serve = |request: Request| compression.apply(&request, files.serve(&request));
+
+        let response = serve(get("/long.txt").with_header("Accept-Encoding", "gzip"));
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        let etag = response.header("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/"), "{}", etag);
+
+        let revalidated = serve(
+            get("/long.txt")
+                .with_header("Accept-Encoding", "gzip")
+                .with_header("If-None-Match", &etag),
+        );
+        assert_eq!(304, revalidated.status);
+        assert_eq!(Some(etag.as_str()), revalidated.header("ETag"));
+        assert_eq!(Some("Accept-Encoding"), revalidated.header("Vary"));
+
+        // Whoever holds the strong tag got the body as it is.
+        let strong = etag.trim_start_matches("W/");
+        let revalidated = serve(get("/long.txt").with_header("If-None-Match", strong));
+        assert_eq!(304, revalidated.status);
+        assert_eq!(Some(strong), revalidated.header("ETag"));
+    }
+
+    #[test]
+    fn allows_only_get_and_head() {
+        let files = StaticFiles::new(document_root()).unwrap();
+
+        assert_eq!(200, files.serve(&Request::test("HEAD", "/notes.txt")).status);
+        let response = files.serve(&Request::test("DELETE", "/notes.txt"));
+        assert_eq!(405, response.status);
+        assert_eq!(Some("GET, HEAD"), response.header("Allow"));
+    }
+
+    #[test]
+    fn needs_an_existing_root() {
+        let root = document_root();
+        assert!(StaticFiles::new(root.join("missing")).is_err());
+        assert!(StaticFiles::new(root.join("notes.txt")).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Jobs whose results come back: `ThreadPool::spawn` and scoped jobs that
+//! may borrow from the caller.
+
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use ThreadPool;
+
+/// An owned permission to join a job: wait for it and take its value.
+///
+/// Dropping the handle detaches the job, which still runs.
+pub struct JobHandle<T> {
+    result: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job, returning its value, or the payload it panicked
+    /// with like `std::thread::JoinHandle::join` does.
+    pub fn join(self) -> thread::Result<T> {
+        self.result
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("the job was dropped before it ran")))
+    }
+}
+
+/// Waits for every job in `handles`, then returns their values in order, or
+/// the panic of the first one that panicked.
+pub fn join_all<T, I>(handles: I) -> thread::Result<Vec<T>>
+where
+    I: IntoIterator<Item = JobHandle<T>>,
+{
+    let results = handles.into_iter().map(JobHandle::join).collect::<Vec<_>>();
+    results.into_iter().collect()
+}
+
+/// Sends what `f` returned or panicked with to its handle.
+fn run_to_handle<F, T>(f: F, sender: mpsc::Sender<thread::Result<T>>) -> bool
+where
+    F: FnOnce() -> T,
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    let panicked = result.is_err();
+    // A failed send means the handle is gone and nobody will see the panic.
+    sender.send(result).is_err() && panicked
+}
+
+impl ThreadPool {
+    /// Runs `f` on a worker and returns a handle to its result.
+    ///
+    /// A panic in `f` is caught and handed to `JobHandle::join`, so it does
+    /// not count towards `Stats::panicked`.
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, result) = mpsc::channel();
+        self.execute(move || {
+            run_to_handle(f, sender);
+        });
+        JobHandle { result }
+    }
+
+    /// Runs `f` with a `Scope` whose jobs may borrow anything that outlives
+    /// the call, and waits for all of them before returning, even if `f`
+    /// panics.
+    ///
+    /// ```
+    /// use bookserver::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let mut counts = vec![0; 8];
+    /// pool.scope(|scope| {
+    ///     for (index, count) in counts.iter_mut().enumerate() {
+    ///         scope.spawn(move || *count = index * 2);
+    ///     }
+    /// });
+    /// assert_eq!(14, counts[7]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics after the jobs are done if one of them panicked and its handle
+    /// had already been dropped, so that the panic is not lost.
+    ///
+    /// Calling `scope` from a job of the same pool can deadlock, as the job
+    /// holds a worker while waiting for the others.
+    pub fn scope<'scope, F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'_, 'scope>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            pending: Arc::new(Pending::default()),
+            _scope: PhantomData,
+        };
+        let result = {
+            let _wait = WaitGuard(&scope.pending);
+            f(&scope)
+        };
+        if scope.pending.state.lock().unwrap().unobserved_panic {
+            panic!("a scoped job panicked");
+        }
+        result
+    }
+}
+
+/// Spawns jobs that may borrow data living for `'scope`. See
+/// `ThreadPool::scope`.
+pub struct Scope<'pool, 'scope> {
+    pool: &'pool ThreadPool,
+    pending: Arc<Pending>,
+    // Invariant, so that `'scope` cannot be shrunk to let shorter borrows in.
+    _scope: PhantomData<&'scope mut &'scope ()>,
+}
+
+impl<'pool, 'scope> Scope<'pool, 'scope> {
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let (sender, result) = mpsc::channel();
+        let pending = Arc::clone(&self.pending);
+        pending.start();
+
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let unobserved_panic = run_to_handle(f, sender);
+            // Everything borrowed is dropped by now; only then may the scope
+            // end.
+            pending.finish(unobserved_panic);
+        });
+        // Safe because `scope` does not return before `finish` above ran, so
+        // the job never outlives what it borrows.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+        self.pool.execute(job);
+
+        JobHandle { result }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    state: Mutex<PendingState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct PendingState {
+    jobs: usize,
+    unobserved_panic: bool,
+}
+
+impl Pending {
+    fn start(&self) {
+        self.state.lock().unwrap().jobs += 1;
+    }
+
+    fn finish(&self, unobserved_panic: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.jobs -= 1;
+        state.unobserved_panic |= unobserved_panic;
+        self.finished.notify_all();
+    }
+}
+
+/// Waits for the scope's jobs when dropped, which also happens while
+/// unwinding from a panicking scope closure.
+struct WaitGuard<'a>(&'a Pending);
+
+impl<'a> Drop for WaitGuard<'a> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        while state.jobs > 0 {
+            state = self.0.finished.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::any::Any;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn panic_message(payload: Box<dyn Any + Send>) -> String {
+        match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(payload) => *payload.downcast::<String>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn spawned_jobs_return_their_values() {
+        let pool = ThreadPool::new(2);
+        let answer = pool.spawn(|| 6 * 7);
+        let greeting = pool.spawn(|| String::from("hello"));
+
+        assert_eq!(42, answer.join().unwrap());
+        assert_eq!("hello", greeting.join().unwrap());
+    }
+
+    #[test]
+    fn spawned_jobs_hand_over_their_panics() {
+        let pool = ThreadPool::new(1);
+        let failed = pool.spawn(|| -> u32 { panic!("no such book") });
+
+        assert_eq!("no such book", panic_message(failed.join().unwrap_err()));
+        assert_eq!(7, pool.spawn(|| 7).join().unwrap());
+    }
+
+    #[test]
+    fn join_all_keeps_the_order() {
+        let pool = ThreadPool::new(4);
+        let handles = (0..20u64)
+            .map(|index| {
+                pool.spawn(move || {
+                    thread::sleep(Duration::from_millis(20 - index));
+                    index * index
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let squares = join_all(handles).unwrap();
+        assert_eq!((0..20).map(|index| index * index).collect::<Vec<_>>(), squares);
+    }
+
+    #[test]
+    fn join_all_waits_for_everything_before_reporting_a_panic() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(2);
+        let mut handles = vec![pool.spawn(|| panic!("first"))];
+        for _ in 0..4 {
+            let finished = Arc::clone(&finished);
+            handles.push(pool.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                finished.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+
+        assert_eq!("first", panic_message(join_all(handles).unwrap_err()));
+        assert_eq!(4, finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn scoped_jobs_borrow_from_the_caller() {
+        let pool = ThreadPool::new(3);
+        let words = vec!["pride", "and", "prejudice"];
+        let mut lengths = vec![0; words.len()];
+
+        let total = pool.scope(|scope| {
+            for (length, word) in lengths.iter_mut().zip(&words) {
+                scope.spawn(move || *length = word.len());
+            }
+            let handles = words.iter().map(|word| scope.spawn(move || word.len())).collect::<Vec<_>>();
+            join_all(handles).unwrap().into_iter().sum::<usize>()
+        });
+
+        assert_eq!(vec![5, 3, 9], lengths);
+        assert_eq!(17, total);
+    }
+
+    #[test]
+    fn scope_waits_for_detached_jobs() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        pool.scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(4, finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn scope_waits_even_if_its_closure_panics() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+                panic!("scope failed");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(1, finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn scope_reports_panics_nobody_joined() {
+        let pool = ThreadPool::new(2);
+
+        let joined = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| scope.spawn(|| panic!("seen")).join().is_err())
+        }));
+        assert!(joined.unwrap());
+
+        let dropped = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| {
+                    // Long enough for the handle to be gone.
+                    thread::sleep(Duration::from_millis(20));
+                    panic!("unseen")
+                });
+            })
+        }));
+        assert_eq!("a scoped job panicked", panic_message(dropped.unwrap_err()));
+    }
+}
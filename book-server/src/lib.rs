@@ -1,7 +1,41 @@
+extern crate crossbeam_deque;
+extern crate flate2;
+#[macro_use]
+extern crate log;
+
+pub mod access_log;
+pub mod cgi;
+pub mod compression;
+pub mod connection;
+mod date;
+pub mod files;
+pub mod jobs;
+pub mod proxy;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod stealing;
+#[cfg(test)]
+mod testing;
+
+pub use compression::Compression;
+pub use jobs::{join_all, JobHandle, Scope};
+pub use request::Request;
+pub use response::{Body, Response};
+pub use router::{Params, Router};
+pub use server::Server;
+pub use stealing::WorkStealingPool;
+
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 trait FnBox {
     fn call_box(self: Box<Self>);
@@ -13,40 +47,156 @@ impl<F: FnOnce()> FnBox for F {
     }
 }
 
-type Job = Box<FnBox + Send + 'static>;
+type Job = Box<dyn FnBox + Send + 'static>;
+
+/// Where jobs wait for a worker. Dropping it tells the workers to stop once
+/// the queue is empty.
+enum Queue {
+    Unbounded(mpsc::Sender<Job>),
+    Bounded(mpsc::SyncSender<Job>),
+}
+
+impl Queue {
+    fn send(&self, job: Job) -> Result<(), mpsc::SendError<Job>> {
+        match *self {
+            Queue::Unbounded(ref sender) => sender.send(job),
+            Queue::Bounded(ref sender) => sender.send(job),
+        }
+    }
+
+    fn try_send(&self, job: Job) -> Result<(), mpsc::TrySendError<Job>> {
+        match *self {
+            Queue::Unbounded(ref sender) => sender.send(job).map_err(|error| mpsc::TrySendError::Disconnected(error.0)),
+            Queue::Bounded(ref sender) => sender.try_send(job),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicUsize,
+    panicked: AtomicUsize,
+}
+
+/// A snapshot of what a `ThreadPool` is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Jobs submitted but not yet picked up by a worker, including those
+    /// whose `execute` is still waiting for room in a bounded queue.
+    pub queued: usize,
+    /// Jobs running right now.
+    pub active: usize,
+    /// Jobs that returned normally.
+    pub completed: usize,
+    /// Jobs that panicked. The worker carries on with the next job. Panics
+    /// of spawned jobs go to their `JobHandle` instead.
+    pub panicked: usize,
+}
+
+/// Why `try_execute` did not take a job.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TryExecuteError {
+    /// The queue is bounded and has no room left.
+    Full,
+    /// Every worker has stopped, so nothing would ever run the job.
+    Disconnected,
+}
+
+impl fmt::Display for TryExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryExecuteError::Full => write!(f, "The thread pool's queue is full"),
+            TryExecuteError::Disconnected => write!(f, "Every worker of the thread pool has stopped"),
+        }
+    }
+}
+
+impl Error for TryExecuteError {}
 
 struct Worker {
     id: usize,
-    thread: thread::JoinHandle<()>,
+    thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        done: mpsc::Sender<usize>,
+        counters: Arc<Counters>,
+    ) -> Worker {
         let thread = thread::spawn(move || {
+            // Reports the exit however the thread ends.
+            let _exit = ExitGuard { id, done };
             loop {
-                let job = receiver.lock().unwrap().recv().unwrap();
+                let message = receiver.lock().unwrap().recv();
+
+                let job = match message {
+                    Ok(job) => job,
+                    Err(_) => {
+                        debug!("Worker {} was told to terminate.", id);
 
-                println!("Worker {} got a job; executing.", id);
+                        break;
+                    }
+                };
+                counters.queued.fetch_sub(1, Ordering::SeqCst);
+                counters.active.fetch_add(1, Ordering::SeqCst);
+                debug!("Worker {} got a job; executing.", id);
 
-                job.call_box();
+                // The job is gone after a panic, so no broken state of it
+                // can be observed.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| job.call_box()));
+
+                counters.active.fetch_sub(1, Ordering::SeqCst);
+                match result {
+                    Ok(()) => counters.completed.fetch_add(1, Ordering::SeqCst),
+                    Err(_) => {
+                        warn!("Worker {} recovered from a panicking job.", id);
+                        counters.panicked.fetch_add(1, Ordering::SeqCst)
+                    }
+                };
             }
         });
 
         Worker {
             id,
-            thread
+            thread: Some(thread),
         }
     }
 }
 
+struct ExitGuard {
+    id: usize,
+    done: mpsc::Sender<usize>,
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        let _ = self.done.send(self.id);
+    }
+}
 
+/// A fixed set of worker threads running jobs from a shared queue.
+///
+/// Shutting down closes the queue rather than sending each worker a
+/// `Terminate` message: workers still finish every job queued before, but
+/// on a full bounded queue such a message would have to wait for room
+/// behind the running jobs, and `shutdown_timeout` could not keep its
+/// timeout.
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    /// Only `None` while shutting down.
+    queue: Option<Queue>,
+    /// In a `Mutex` only so that the pool stays `Sync`; just
+    /// `shutdown_timeout` receives from it.
+    done: Mutex<mpsc::Receiver<usize>>,
+    counters: Arc<Counters>,
 }
 
 impl ThreadPool {
-    /// Create a new ThreadPool.
+    /// Create a new ThreadPool whose queue grows as needed.
     ///
     /// The size is the number of threads in the pool.
     ///
@@ -54,29 +204,304 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel();
+        ThreadPool::start(size, Queue::Unbounded(sender), receiver)
+    }
+
+    /// Create a new ThreadPool that holds at most `capacity` jobs waiting
+    /// for a worker. Beyond that `execute` blocks and `try_execute` fails.
+    ///
+    /// With a capacity of zero, jobs are only accepted by an idle worker.
+    ///
+    /// # Panics
+    ///
+    /// The `bounded` function will panic if the size is zero.
+    pub fn bounded(size: usize, capacity: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        ThreadPool::start(size, Queue::Bounded(sender), receiver)
+    }
+
+    fn start(size: usize, queue: Queue, receiver: mpsc::Receiver<Job>) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let (done_sender, done) = mpsc::channel();
+        let counters = Arc::new(Counters::default());
 
         let mut workers = Vec::with_capacity(size);
          for id in 0..size {
-             let worker = Worker::new(id, Arc::clone(&receiver));
+             let worker = Worker::new(id, Arc::clone(&receiver), done_sender.clone(), Arc::clone(&counters));
              workers.push(worker);
          }
 
         ThreadPool {
             workers,
-            sender,
+            queue: Some(queue),
+            done: Mutex::new(done),
+            counters,
         }
     }
 
+    /// Runs `f` on a worker, waiting for room in the queue if it is bounded
+    /// and full.
     pub fn execute<F>(&self, f: F)
         where
             F: FnOnce() + Send + 'static
     {
         let job = Box::new(f);
 
-        self.sender.send(job).unwrap();
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        self.queue().send(job).unwrap();
+    }
+
+    /// Runs `f` on a worker, unless the queue is bounded and full or no
+    /// worker is left.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        let job = Box::new(f);
+
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        let error = match self.queue().try_send(job) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => TryExecuteError::Full,
+            Err(mpsc::TrySendError::Disconnected(_)) => TryExecuteError::Disconnected,
+        };
+        self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+        Err(error)
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            queued: self.counters.queued.load(Ordering::SeqCst),
+            active: self.counters.active.load(Ordering::SeqCst),
+            completed: self.counters.completed.load(Ordering::SeqCst),
+            panicked: self.counters.panicked.load(Ordering::SeqCst),
+        }
+    }
+
+    fn queue(&self) -> &Queue {
+        self.queue.as_ref().expect("the pool is shutting down")
+    }
+
+    /// Shuts the pool down like dropping it does, but waits at most `timeout`
+    /// for the jobs already submitted to finish.
+    ///
+    /// Returns whether every worker stopped in time. Workers that did not are
+    /// left to finish the queue in the background.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> bool {
+        self.terminate();
+
+        let deadline = Instant::now() + timeout;
+        let done = self.done.lock().unwrap();
+        let mut running = self.workers.iter().filter(|worker| worker.thread.is_some()).count();
+        while running > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match done.recv_timeout(remaining) {
+                Ok(id) => {
+                    if let Some(thread) = self.workers[id].thread.take() {
+                        let _ = thread.join();
+                    }
+                    running -= 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        // Dropping the handles of the stragglers detaches them, so that
+        // `drop` does not wait for them after all.
+        for worker in &mut self.workers {
+            if worker.thread.take().is_some() {
+                warn!("Worker {} did not stop in time.", worker.id);
+            }
+        }
+        running == 0
+    }
+
+    /// Asks every worker to stop once the jobs queued before are done.
+    fn terminate(&mut self) {
+        debug!("Closing the queue; workers stop once it is empty.");
+
+        self.queue = None;
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        if self.workers.iter().all(|worker| worker.thread.is_none()) {
+            return;
+        }
+        self.terminate();
+
+        debug!("Shutting down all workers.");
+
+        for worker in &mut self.workers {
+            debug!("Shutting down worker {}", worker.id);
+
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn pools_can_be_shared_between_threads() {
+        assert_sync::<ThreadPool>();
+        assert_sync::<WorkStealingPool>();
+
+        let pool = Arc::new(ThreadPool::new(2));
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::clone(&pool);
+        thread::spawn(move || shared.execute(move || sender.send(42).unwrap()))
+            .join()
+            .unwrap();
+        assert_eq!(42, receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn drop_waits_for_in_flight_and_queued_jobs() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(2);
+        for _ in 0..6 {
+            let completed = Arc::clone(&completed);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(50));
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        drop(pool);
+
+        assert_eq!(6, completed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shutdown_timeout_lets_quick_jobs_finish() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(4);
+        for _ in 0..4 {
+            let completed = Arc::clone(&completed);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+        assert_eq!(4, completed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shutdown_timeout_gives_up_on_slow_jobs() {
+        let pool = ThreadPool::new(2);
+        pool.execute(|| thread::sleep(Duration::from_millis(500)));
+        pool.execute(|| {});
+
+        let started = Instant::now();
+        assert!(!pool.shutdown_timeout(Duration::from_millis(50)));
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+        for _ in 0..3 {
+            let completed = Arc::clone(&completed);
+            pool.execute(move || {
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        wait_for(&pool, |stats| stats.completed == 3);
+        assert_eq!(1, pool.stats().panicked);
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+        assert_eq!(3, completed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn bounded_queues_reject_jobs_when_full() {
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        let pool = ThreadPool::bounded(1, 1);
+
+        let blocked = Arc::clone(&gate);
+        pool.execute(move || {
+            let _ = blocked.lock().unwrap().recv();
+        });
+        wait_for(&pool, |stats| stats.active == 1);
+
+        assert_eq!(Ok(()), pool.try_execute(|| {}));
+        assert_eq!(Err(TryExecuteError::Full), pool.try_execute(|| {}));
+        assert_eq!(
+            Stats {
+                queued: 1,
+                active: 1,
+                completed: 0,
+                panicked: 0,
+            },
+            pool.stats()
+        );
+
+        release.send(()).unwrap();
+        wait_for(&pool, |stats| stats.completed == 2);
+        assert_eq!(Ok(()), pool.try_execute(|| {}));
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shutdown_timeout_is_not_held_up_by_a_full_queue() {
+        let pool = ThreadPool::bounded(1, 1);
+        pool.execute(|| thread::sleep(Duration::from_millis(500)));
+        pool.execute(|| {});
+
+        let started = Instant::now();
+        assert!(!pool.shutdown_timeout(Duration::from_millis(50)));
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn try_execute_fails_once_every_worker_stopped() {
+        let (sender, receiver) = mpsc::channel();
+        drop(receiver);
+        let pool = ThreadPool {
+            workers: Vec::new(),
+            queue: Some(Queue::Unbounded(sender)),
+            done: Mutex::new(mpsc::channel().1),
+            counters: Arc::new(Counters::default()),
+        };
+
+        assert_eq!(Err(TryExecuteError::Disconnected), pool.try_execute(|| {}));
+        assert_eq!(Stats::default(), pool.stats());
+    }
+
+    #[test]
+    fn unbounded_queues_always_accept_jobs() {
+        let pool = ThreadPool::new(1);
+        for _ in 0..100 {
+            assert_eq!(Ok(()), pool.try_execute(|| {}));
+        }
+        wait_for(&pool, |stats| stats.completed == 100);
+        assert_eq!(Stats { completed: 100, ..Stats::default() }, pool.stats());
+    }
+
+    /// Polls the stats of `pool` until `done` holds, for up to five seconds.
+    fn wait_for<F: Fn(&Stats) -> bool>(pool: &ThreadPool, done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&pool.stats()) {
+            assert!(Instant::now() < deadline, "gave up waiting: {:?}", pool.stats());
+            thread::sleep(Duration::from_millis(5));
+        }
     }
 }
@@ -0,0 +1,589 @@
+//! A reverse proxy: requests under a path prefix are forwarded to another
+//! server, and its responses passed back.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use request::{self, Framing, Limits, ParseError, Request, MAX_CHUNK_LINE};
+use response::{self, Response};
+
+/// How long connecting to an upstream may take.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an upstream may go quiet, before its response starts or in the
+/// middle of its body.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that describe one connection rather than the message, and so are
+/// not passed along, per RFC 7230. `Expect` is dropped too: the body is on
+/// its way by the time the upstream could answer it.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Expect",
+];
+
+struct Route {
+    /// Without a trailing slash, so `/api` stands for `/api` and `/api/...`.
+    prefix: String,
+    upstream: String,
+}
+
+/// Forwards requests by path prefix, one upstream connection per request.
+///
+/// The path is forwarded as it is, prefix included. Response bodies are
+/// streamed to the client as they arrive.
+///
+/// Given to `Server::proxy`, it sees requests before the router does and
+/// streams their bodies upstream as they arrive too, chunked ones as
+/// chunks, with no `Limits::max_body_bytes` to keep to.
+///
+/// ```no_run
+/// use bookserver::proxy::Proxy;
+/// use bookserver::{Router, Server};
+///
+/// let proxy = Proxy::new().route("/api", "127.0.0.1:9000");
+/// Server::new(Router::new()).proxy(proxy).listen("127.0.0.1:7878").unwrap();
+/// ```
+///
+/// Its `handle` also serves from a handler, but by then the body has been
+/// read whole.
+pub struct Proxy {
+    routes: Vec<Route>,
+    connect_timeout: Duration,
+    timeout: Duration,
+}
+
+impl Default for Proxy {
+    fn default() -> Proxy {
+        Proxy::new()
+    }
+}
+
+impl Proxy {
+    pub fn new() -> Proxy {
+        Proxy {
+            routes: Vec::new(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Forwards requests for `prefix` and everything below it to
+    /// `upstream`, a `host:port` that also becomes the `Host` header. The
+    /// longest matching prefix wins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix` does not start with a `/`.
+    pub fn route(mut self, prefix: &str, upstream: &str) -> Proxy {
+        assert!(prefix.starts_with('/'), "proxy prefix {:?} does not start with '/'", prefix);
+        self.routes.push(Route {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            upstream: upstream.to_string(),
+        });
+        self
+    }
+
+    /// How long connecting to an upstream may take. Defaults to
+    /// `DEFAULT_CONNECT_TIMEOUT`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Proxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long an upstream may go quiet. Defaults to `DEFAULT_TIMEOUT`.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The upstream's response to `request`, or `None` if no prefix
+    /// matches. Answers 502 if the upstream cannot be reached or sends
+    /// nonsense, and 504 if it times out.
+    pub fn handle(&self, request: &Request) -> Option<Response> {
+        let route = self.route_for(request)?;
+        let mut body = &request.body[..];
+        let result = self.forward(request, &route.upstream, &mut body, Some(request.body.len() as u64));
+        Some(answer(request, route, result))
+    }
+
+    /// Like `handle`, for a request whose body is still on `reader`, framed
+    /// by `framing`. Also tells whether the body was read to its end, as
+    /// the connection can only carry on if it was.
+    pub(crate) fn stream<R>(&self, request: &Request, framing: Framing, reader: &mut R) -> Option<(Response, bool)>
+    where
+        R: BufRead,
+    {
+        let route = self.route_for(request)?;
+        Some(match framing {
+            Framing::Length(length) => {
+                let mut body = reader.take(length);
+                let result = self.forward(request, &route.upstream, &mut body, Some(length));
+                (answer(request, route, result), body.limit() == 0)
+            }
+            Framing::Chunked => {
+                let mut body = Chunked::new(reader);
+                let result = self.forward(request, &route.upstream, &mut body, None);
+                (answer(request, route, result), body.done)
+            }
+        })
+    }
+
+    /// The route for `request`, the longest matching prefix.
+    fn route_for(&self, request: &Request) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| matches_prefix(&request.path, &route.prefix))
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    /// Sends `request` with `body`, of `length` if known and chunked if
+    /// not, and reads the head of the response.
+    fn forward(
+        &self,
+        request: &Request,
+        upstream: &str,
+        body: &mut dyn Read,
+        length: Option<u64>,
+    ) -> Result<Response, Failure> {
+        let stream = self.connect(upstream)?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(Failure::from)?;
+        stream.set_write_timeout(Some(self.timeout)).map_err(Failure::from)?;
+        let mut writer = &stream;
+        write_head(&mut writer, request, upstream, length).map_err(Failure::from)?;
+        match length {
+            Some(_) => response::copy(body, &mut writer),
+            None => response::write_chunked(body, &mut writer),
+        }
+        .and_then(|_| writer.flush())
+        .map_err(Failure::from)?;
+        read_response(BufReader::new(stream), request.method == "HEAD")
+    }
+
+    fn connect(&self, upstream: &str) -> Result<TcpStream, Failure> {
+        let mut last_error = None;
+        for address in upstream.to_socket_addrs().map_err(Failure::from)? {
+            match TcpStream::connect_timeout(&address, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error.map_or(Failure::Invalid("the upstream has no address"), Failure::from))
+    }
+}
+
+/// The response, or what to say instead of it.
+fn answer(request: &Request, route: &Route, result: Result<Response, Failure>) -> Response {
+    result.unwrap_or_else(|failure| {
+        warn!("Proxying {} to {} failed: {}", request.path, route.upstream, failure);
+        match failure {
+            Failure::TimedOut => Response::text(504, "Gateway timeout\n"),
+            _ => Response::text(502, "Bad gateway\n"),
+        }
+    })
+}
+
+fn matches_prefix(path: &str, prefix: &str) -> bool {
+    path.starts_with(prefix) && matches!(path.as_bytes().get(prefix.len()), None | Some(b'/'))
+}
+
+/// Why an upstream did not answer.
+#[derive(Debug)]
+enum Failure {
+    TimedOut,
+    Io(io::Error),
+    Invalid(&'static str),
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Failure {
+        match error.kind() {
+            // Read timeouts show up as WouldBlock on Unix.
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Failure::TimedOut,
+            _ => Failure::Io(error),
+        }
+    }
+}
+
+impl From<ParseError> for Failure {
+    fn from(error: ParseError) -> Failure {
+        match error {
+            ParseError::Io(error) => Failure::from(error),
+            ParseError::Malformed(reason) => Failure::Invalid(reason),
+            _ => Failure::Invalid("the response head is too large"),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::TimedOut => write!(f, "timed out"),
+            Failure::Io(ref error) => write!(f, "{}", error),
+            Failure::Invalid(reason) => write!(f, "invalid response: {}", reason),
+        }
+    }
+}
+
+fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP.iter().any(|header| header.eq_ignore_ascii_case(name))
+        || connection.is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case(name)))
+}
+
+fn write_head<W: Write>(writer: &mut W, request: &Request, upstream: &str, length: Option<u64>) -> io::Result<()> {
+    let mut head = match request.query {
+        Some(ref query) => format!("{} {}?{} HTTP/1.1\r\n", request.method, request.path, query),
+        None => format!("{} {} HTTP/1.1\r\n", request.method, request.path),
+    };
+    head.push_str(&format!("Host: {}\r\n", upstream));
+    let connection = request.header("Connection");
+    // Every hop so far, however many headers they came in.
+    let mut forwarded_for = Vec::new();
+    for (name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for.push(value.clone());
+        } else if !(is_hop_by_hop(name, connection)
+            || name.eq_ignore_ascii_case("Host")
+            || name.eq_ignore_ascii_case("Content-Length"))
+        {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if let Some(remote) = request.remote {
+        forwarded_for.push(remote.to_string());
+    }
+    if !forwarded_for.is_empty() {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for.join(", ")));
+    }
+    let framed = request.header("Content-Length").is_some() || request.header("Transfer-Encoding").is_some();
+    match length {
+        Some(length) if framed || length > 0 => head.push_str(&format!("Content-Length: {}\r\n", length)),
+        Some(_) => {}
+        None => head.push_str("Transfer-Encoding: chunked\r\n"),
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    writer.write_all(head.as_bytes())
+}
+
+/// Reads the upstream's response head, leaving the body to be streamed.
+fn read_response<R>(mut reader: R, head_request: bool) -> Result<Response, Failure>
+where
+    R: BufRead + Send + 'static,
+{
+    let limits = Limits::default();
+    let (status, headers) = loop {
+        let mut remaining = limits.max_header_bytes;
+        let status_line = request::read_line(&mut reader, &mut remaining)?
+            .ok_or(Failure::Invalid("the connection closed before a response"))?;
+        let status = parse_status_line(&status_line).ok_or(Failure::Invalid("invalid status line"))?;
+        let mut headers = Vec::new();
+        loop {
+            let line = request::read_line(&mut reader, &mut remaining)?
+                .ok_or(Failure::Invalid("the connection closed in the headers"))?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == limits.max_headers {
+                return Err(Failure::Invalid("too many headers"));
+            }
+            headers.push(request::parse_header(&line)?);
+        }
+        // Interim responses such as 100 Continue are not passed on.
+        match status {
+            101 => return Err(Failure::Invalid("protocol upgrades are not supported")),
+            100..=199 => continue,
+            _ => break (status, headers),
+        }
+    };
+
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+    };
+    let chunked = header("Transfer-Encoding").is_some_and(|codings| {
+        codings
+            .rsplit(',')
+            .next()
+            .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+    });
+    let length = match header("Content-Length") {
+        Some(length) => Some(length.parse::<u64>().map_err(|_| Failure::Invalid("invalid Content-Length"))?),
+        None => None,
+    };
+
+    let mut response = Response::new(status);
+    let connection = header("Connection");
+    for (name, value) in &headers {
+        if !is_hop_by_hop(name, connection) && !name.eq_ignore_ascii_case("Content-Length") {
+            response.headers.push((name.clone(), value.clone()));
+        }
+    }
+    // A HEAD response's length is that of the body it leaves out.
+    Ok(if head_request || status == 204 || status == 304 {
+        response.with_stream(io::empty(), length)
+    } else if chunked {
+        response.with_stream(Chunked::new(reader), None)
+    } else if let Some(length) = length {
+        response.with_stream(reader.take(length), Some(length))
+    } else {
+        // Until the upstream closes, which it does as asked.
+        response.with_stream(reader, None)
+    })
+}
+
+fn parse_status_line(line: &str) -> Option<u16> {
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next()) {
+        (Some("HTTP/1.0"), Some(status)) | (Some("HTTP/1.1"), Some(status)) if status.len() == 3 => {
+            status.parse().ok().filter(|status| (100..600).contains(status))
+        }
+        _ => None,
+    }
+}
+
+/// Decodes a chunked body as it is read.
+struct Chunked<R> {
+    reader: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(reader: R) -> Chunked<R> {
+        Chunked {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut budget = MAX_CHUNK_LINE;
+        match request::read_line(&mut self.reader, &mut budget) {
+            Ok(Some(line)) => Ok(line),
+            Ok(None) => Err(io::Error::new(ErrorKind::UnexpectedEof, "the chunked body ended early")),
+            Err(ParseError::Io(error)) => Err(error),
+            Err(_) => Err(io::Error::new(ErrorKind::InvalidData, "invalid chunked body")),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.done || buffer.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.line()?;
+            self.remaining = request::parse_chunk_size(&line)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid chunk size"))?;
+            if self.remaining == 0 {
+                // Trailers, if any, are dropped.
+                while !self.line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let wanted = cmp::min(buffer.len() as u64, self.remaining) as usize;
+        let read = self.reader.read(&mut buffer[..wanted])?;
+        if read == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "the chunked body ended early"));
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 && !self.line()?.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "chunk not followed by a line break"));
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    /// A stub upstream that answers one connection with `response` and
+    /// hands back the raw request it got.
+    fn upstream(response: &'static [u8]) -> (SocketAddr, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut raw = String::new();
+            while !raw.ends_with("\r\n\r\n") {
+                if reader.read_line(&mut raw).unwrap() == 0 {
+                    break;
+                }
+            }
+            if raw.contains("Transfer-Encoding: chunked\r\n") {
+                // Kept raw, to show the chunks.
+                while !raw.ends_with("\r\n0\r\n\r\n") {
+                    reader.read_line(&mut raw).unwrap();
+                }
+            } else {
+                let length = raw
+                    .lines()
+                    .find(|line| line.starts_with("Content-Length: "))
+                    .map_or(0, |line| line["Content-Length: ".len()..].parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                raw.push_str(&String::from_utf8(body).unwrap());
+            }
+            (&stream).write_all(response).unwrap();
+            raw
+        });
+        (address, received)
+    }
+
+    #[test]
+    fn streams_request_bodies() {
+        let (address, received) = upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = Proxy::new().route("/", &address.to_string());
+        let request = Request::test("PUT", "/books/7").with_header("Transfer-Encoding", "chunked");
+        let raw = b"4\r\nDune\r\n5;ext=1\r\n Emma\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1";
+        let mut connection = Cursor::new(&raw[..]);
+
+        let (response, body_read) = proxy.stream(&request, Framing::Chunked, &mut connection).unwrap();
+        assert_eq!(204, response.status);
+        assert!(body_read);
+        let raw = received.join().unwrap();
+        assert!(raw.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", raw);
+        assert!(raw.ends_with("\r\n\r\n4\r\nDune\r\n5\r\n Emma\r\n0\r\n\r\n"), "{}", raw);
+        assert_eq!(b"GET / HTTP/1.1", &connection.get_ref()[connection.position() as usize..]);
+
+        // Not past its length, and not at all when the upstream is gone.
+        let (address, received) = upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = Proxy::new().route("/", &address.to_string());
+        let mut connection = Cursor::new(&b"DuneGET / HTTP/1.1"[..]);
+        let request = Request::test("PUT", "/books/7").with_header("Content-Length", "4");
+        let (_, body_read) = proxy.stream(&request, Framing::Length(4), &mut connection).unwrap();
+        assert!(body_read);
+        assert!(received.join().unwrap().ends_with("\r\nContent-Length: 4\r\nConnection: close\r\n\r\nDune"));
+        assert_eq!(4, connection.position());
+
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let proxy = Proxy::new().route("/", &address.to_string());
+        let mut connection = Cursor::new(&b"Dune"[..]);
+        let (response, body_read) = proxy.stream(&request, Framing::Length(4), &mut connection).unwrap();
+        assert_eq!(502, response.status);
+        assert!(!body_read);
+    }
+
+    fn text(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn forwards_requests_and_responses() {
+        let (address, received) =
+            upstream(b"HTTP/1.1 201 Created\r\nLocation: /books/7\r\nConnection: close\r\nContent-Length: 5\r\n\r\nsaved");
+        let proxy = Proxy::new().route("/api/", &address.to_string());
+        let mut books = Request::test("POST", "/api/books")
+            .with_header("Host", "books.example")
+            .with_header("Content-Length", "4")
+            .with_header("Connection", "keep-alive, X-Secret")
+            .with_header("X-Secret", "hop")
+            .with_header("X-Forwarded-For", "192.0.2.1")
+            .with_header("Accept", "*/*")
+            .with_header("x-forwarded-for", "198.51.100.2, 198.51.100.3")
+            .with_body("Dune")
+            .with_remote("10.0.0.7");
+        books.query = Some("draft=1".to_string());
+
+        let response = proxy.handle(&books).unwrap();
+        assert_eq!(201, response.status);
+        assert_eq!(Some("/books/7"), response.header("Location"));
+        assert_eq!(None, response.header("Connection"));
+        assert_eq!(Some(5), response.body.len());
+        assert_eq!("saved", text(response));
+
+        assert_eq!(
+            format!(
+                "POST /api/books?draft=1 HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\
+                 X-Forwarded-For: 192.0.2.1, 198.51.100.2, 198.51.100.3, 10.0.0.7\r\nContent-Length: 4\r\nConnection: close\r\n\r\nDune",
+                address
+            ),
+            received.join().unwrap()
+        );
+    }
+
+    #[test]
+    fn streams_chunked_and_unframed_bodies() {
+        let (address, _) = upstream(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              6\r\nhello,\r\n6;ext=1\r\n world\r\n0\r\nTrailer: x\r\n\r\n",
+        );
+        let response = Proxy::new().route("/", &address.to_string()).handle(&Request::test("GET", "/")).unwrap();
+        assert_eq!(None, response.header("Transfer-Encoding"));
+        assert_eq!(None, response.body.len());
+        assert_eq!("hello, world", text(response));
+
+        let (address, _) = upstream(b"HTTP/1.0 200 OK\r\n\r\nuntil the end");
+        let response = Proxy::new().route("/", &address.to_string()).handle(&Request::test("GET", "/")).unwrap();
+        assert_eq!("until the end", text(response));
+
+        let (address, _) = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 42\r\n\r\n");
+        let response = Proxy::new().route("/", &address.to_string()).handle(&Request::test("HEAD", "/")).unwrap();
+        assert_eq!(Some(42), response.body.len());
+    }
+
+    #[test]
+    fn routes_by_the_longest_prefix() {
+        let (books, _) = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nbooks");
+        let (api, _) = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\napi");
+        let proxy = Proxy::new().route("/api", &api.to_string()).route("/api/books", &books.to_string());
+
+        assert_eq!("books", text(proxy.handle(&Request::test("GET", "/api/books/1")).unwrap()));
+        assert_eq!("api", text(proxy.handle(&Request::test("GET", "/api")).unwrap()));
+        assert!(proxy.handle(&Request::test("GET", "/apiary")).is_none());
+        assert!(proxy.handle(&Request::test("GET", "/")).is_none());
+    }
+
+    #[test]
+    fn answers_502_for_broken_upstreams() {
+        // Nothing listens on a port that was just released.
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let proxy = Proxy::new().route("/", &address.to_string());
+        assert_eq!(502, proxy.handle(&Request::test("GET", "/")).unwrap().status);
+
+        let (address, _) = upstream(b"SMTP ready\r\n\r\n");
+        let proxy = Proxy::new().route("/", &address.to_string());
+        assert_eq!(502, proxy.handle(&Request::test("GET", "/")).unwrap().status);
+
+        let (address, _) = upstream(b"");
+        let proxy = Proxy::new().route("/", &address.to_string());
+        assert_eq!(502, proxy.handle(&Request::test("GET", "/")).unwrap().status);
+    }
+
+    #[test]
+    fn answers_504_for_silent_upstreams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy::new()
+            .route("/", &listener.local_addr().unwrap().to_string())
+            .timeout(Duration::from_millis(100));
+
+        // Accepted by the backlog, but never answered.
+        let response = proxy.handle(&Request::test("GET", "/")).unwrap();
+        assert_eq!(504, response.status);
+        drop(listener);
+    }
+}
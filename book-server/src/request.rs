@@ -0,0 +1,759 @@
+//! Parsing of HTTP/1.x requests.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+
+/// Bounds on what a client may send, so that a request cannot make the server
+/// buffer without end.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Bytes in the request line and headers together.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_bytes: 8 * 1024,
+            max_headers: 100,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Bytes in one chunk size line, extensions included.
+pub(crate) const MAX_CHUNK_LINE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// The path of the request target, without the query.
+    pub path: String,
+    /// Everything after the `?` of the request target, if there was one.
+    pub query: Option<String>,
+    /// `HTTP/1.0` or `HTTP/1.1`.
+    pub version: String,
+    /// Headers in the order they were sent, names as sent.
+    pub headers: Vec<(String, String)>,
+    /// The body, already decoded if it was sent chunked.
+    pub body: Vec<u8>,
+    /// Where the request came from, as filled in by `serve_connection`.
+    pub remote: Option<IpAddr>,
+    /// The server's end of the connection, as filled in by
+    /// `serve_connection`.
+    pub local: Option<SocketAddr>,
+}
+
+impl Request {
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Builds requests for tests: `Request::test("GET", "/").with_header(..)`.
+#[cfg(test)]
+impl Request {
+    pub(crate) fn test(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: None,
+            version: "HTTP/1.1".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote: None,
+            local: None,
+        }
+    }
+
+    pub(crate) fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub(crate) fn with_body(mut self, body: &str) -> Request {
+        self.body = body.as_bytes().to_vec();
+        self
+    }
+
+    pub(crate) fn with_remote(mut self, remote: &str) -> Request {
+        self.remote = Some(remote.parse().unwrap());
+        self
+    }
+
+    pub(crate) fn with_local(mut self, local: &str) -> Request {
+        self.local = Some(local.parse().unwrap());
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request does not follow the HTTP/1.x syntax.
+    Malformed(&'static str),
+    HeadersTooLarge,
+    TooManyHeaders,
+    BodyTooLarge,
+    /// A transfer coding other than `chunked`.
+    UnsupportedTransferEncoding,
+    UnsupportedVersion,
+    /// The connection closed or failed before the request was complete.
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status line to answer with, if the connection is still usable.
+    pub fn status(&self) -> Option<(u16, &'static str)> {
+        match *self {
+            ParseError::Malformed(_) => Some((400, "Bad Request")),
+            ParseError::HeadersTooLarge | ParseError::TooManyHeaders => {
+                Some((431, "Request Header Fields Too Large"))
+            }
+            ParseError::BodyTooLarge => Some((413, "Payload Too Large")),
+            ParseError::UnsupportedTransferEncoding => Some((501, "Not Implemented")),
+            ParseError::UnsupportedVersion => Some((505, "HTTP Version Not Supported")),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Malformed(reason) => write!(f, "Malformed request: {}", reason),
+            ParseError::HeadersTooLarge => write!(f, "Request headers are too large"),
+            ParseError::TooManyHeaders => write!(f, "Request has too many headers"),
+            ParseError::BodyTooLarge => write!(f, "Request body is too large"),
+            ParseError::UnsupportedTransferEncoding => write!(f, "Only the chunked Transfer-Encoding is supported"),
+            ParseError::UnsupportedVersion => write!(f, "HTTP version is not supported"),
+            ParseError::Io(ref error) => write!(f, "Error reading request: {}", error),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> ParseError {
+        ParseError::Io(error)
+    }
+}
+
+/// Reads one request from `reader`.
+///
+/// Returns `Ok(None)` if the connection was closed before the request
+/// started, which is how clients end a persistent connection.
+pub fn read_request<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Request>, ParseError> {
+    let mut head = match read_head(reader, limits)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    read_body(reader, &mut head, limits)?;
+    Ok(Some(head.request))
+}
+
+/// How a request body is delimited on the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Framing {
+    Length(u64),
+    Chunked,
+}
+
+/// A request whose body is still on the connection.
+pub(crate) struct Head {
+    /// With an empty body until `read_body`.
+    pub request: Request,
+    pub framing: Framing,
+    /// What is left of the header budget, which trailers count against.
+    remaining: usize,
+}
+
+/// Reads the request line and headers, leaving the body to `read_body` or
+/// to whoever streams it. `Ok(None)` is as for `read_request`.
+pub(crate) fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Head>, ParseError> {
+    let mut remaining = limits.max_header_bytes;
+
+    let request_line = match read_line(reader, &mut remaining)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let (method, target, version) = parse_request_line(&request_line)?;
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], Some(target[index + 1..].to_string())),
+        None => (target, None),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut remaining)?
+            .ok_or(ParseError::Malformed("connection closed in the headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        headers.push(parse_header(&line)?);
+    }
+
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+        remote: None,
+        local: None,
+    };
+    let framing = if is_chunked(&request)? {
+        Framing::Chunked
+    } else {
+        Framing::Length(content_length(&request)?)
+    };
+    Ok(Some(Head {
+        request,
+        framing,
+        remaining,
+    }))
+}
+
+/// Reads the body of the request `read_head` returned, within the limits.
+pub(crate) fn read_body<R: BufRead>(reader: &mut R, head: &mut Head, limits: &Limits) -> Result<(), ParseError> {
+    let length = match head.framing {
+        Framing::Chunked => {
+            head.request.body = read_chunked(reader, limits, &mut head.remaining)?;
+            return Ok(());
+        }
+        Framing::Length(length) => length,
+    };
+    if length > limits.max_body_bytes as u64 {
+        return Err(ParseError::BodyTooLarge);
+    }
+    let body = &mut head.request.body;
+    body.reserve_exact(length as usize);
+    reader.take(length).read_to_end(body)?;
+    if (body.len() as u64) < length {
+        return Err(ParseError::Malformed("connection closed in the body"));
+    }
+    Ok(())
+}
+
+/// Reads a line without its CRLF (or bare LF), counting it against the
+/// header budget. Returns `None` at the end of the stream.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R, remaining: &mut usize) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    // One byte past the budget tells a full budget from an overflow.
+    reader.take(*remaining as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.len() > *remaining {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    *remaining -= line.len();
+    if line.pop() != Some(b'\n') {
+        return Err(ParseError::Malformed("connection closed in the headers"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ParseError::Malformed("headers are not valid UTF-8"))
+}
+
+fn parse_request_line(line: &str) -> Result<(&str, &str, &str), ParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::Malformed("invalid request line")),
+    };
+    if !is_token(method) {
+        return Err(ParseError::Malformed("invalid method"));
+    }
+    if !(target.starts_with('/') || target == "*") || !target.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(ParseError::Malformed("invalid request target"));
+    }
+    match version {
+        "HTTP/1.0" | "HTTP/1.1" => Ok((method, target, version)),
+        _ if version.starts_with("HTTP/") => Err(ParseError::UnsupportedVersion),
+        _ => Err(ParseError::Malformed("invalid HTTP version")),
+    }
+}
+
+pub(crate) fn parse_header(line: &str) -> Result<(String, String), ParseError> {
+    let colon = line.find(':').ok_or(ParseError::Malformed("header without a colon"))?;
+    let name = &line[..colon];
+    // Also rejects obsolete line folding, which starts with whitespace.
+    if !is_token(name) {
+        return Err(ParseError::Malformed("invalid header name"));
+    }
+    let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(ParseError::Malformed("invalid header value"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Whether the body is sent chunked. `chunked` is the only transfer coding
+/// understood, and it may not come with a `Content-Length`.
+fn is_chunked(request: &Request) -> Result<bool, ParseError> {
+    let codings = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    if codings.is_empty() {
+        return Ok(false);
+    }
+    if codings.len() > 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+        return Err(ParseError::UnsupportedTransferEncoding);
+    }
+    // Which of the two frames the body is another smuggling vector.
+    if request.header("Content-Length").is_some() {
+        return Err(ParseError::Malformed("both Transfer-Encoding and Content-Length"));
+    }
+    Ok(true)
+}
+
+/// Reads a chunked body up to its last chunk, discarding any trailer
+/// fields. Trailers count against what is left of the header budget.
+fn read_chunked<R: BufRead>(reader: &mut R, limits: &Limits, remaining: &mut usize) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let mut line_budget = MAX_CHUNK_LINE;
+        let line = match read_line(reader, &mut line_budget) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(ParseError::Malformed("connection closed in the body")),
+            Err(ParseError::HeadersTooLarge) => return Err(ParseError::Malformed("chunk size line too long")),
+            Err(error) => return Err(error),
+        };
+        let size = parse_chunk_size(&line).ok_or(ParseError::Malformed("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        if size > (limits.max_body_bytes - body.len()) as u64 {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let start = body.len();
+        reader.take(size).read_to_end(&mut body)?;
+        if ((body.len() - start) as u64) < size {
+            return Err(ParseError::Malformed("connection closed in the body"));
+        }
+        let mut line_budget = 2;
+        match read_line(reader, &mut line_budget) {
+            Ok(Some(ref end)) if end.is_empty() => {}
+            _ => return Err(ParseError::Malformed("chunk not followed by a line break")),
+        }
+    }
+    loop {
+        let line = read_line(reader, remaining)?.ok_or(ParseError::Malformed("connection closed in the trailers"))?;
+        if line.is_empty() {
+            return Ok(body);
+        }
+        parse_header(&line)?;
+    }
+}
+
+/// The size on a chunk size line. Chunk extensions are allowed and
+/// ignored.
+pub(crate) fn parse_chunk_size(line: &str) -> Option<u64> {
+    let size = line.split(';').next().unwrap().trim_end_matches([' ', '\t']);
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+fn content_length(request: &Request) -> Result<u64, ParseError> {
+    let mut lengths = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, value)| value);
+    let length = match lengths.next() {
+        Some(length) => length,
+        None => return Ok(0),
+    };
+    // Disagreeing lengths are a classic request smuggling vector.
+    if lengths.any(|other| other != length) {
+        return Err(ParseError::Malformed("conflicting Content-Length headers"));
+    }
+    if length.is_empty() || !length.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(ParseError::Malformed("invalid Content-Length"));
+    }
+    length
+        .parse()
+        .map_err(|_| ParseError::Malformed("invalid Content-Length"))
+}
+
+/// Decodes the `%XX` escapes of a path or query. Returns `None` for broken
+/// escapes or a result that is not UTF-8.
+pub(crate) fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            if !hex.iter().all(|byte| byte.is_ascii_hexdigit()) {
+                return None;
+            }
+            let hex = str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Whether `text` is an RFC 7230 token, as methods and header names must be.
+fn is_token(text: &str) -> bool {
+    !text.is_empty()
+        && text.bytes().all(|byte| {
+            byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        read_request(&mut Cursor::new(raw), &Limits::default())
+    }
+
+    fn parse_ok(raw: &[u8]) -> Request {
+        parse(raw).unwrap().unwrap()
+    }
+
+    fn malformed(raw: &[u8]) -> bool {
+        matches!(parse(raw), Err(ParseError::Malformed(_)))
+    }
+
+    #[test]
+    fn parses_a_simple_get() {
+        let request = parse_ok(b"GET / HTTP/1.1\r\nHost: localhost:7878\r\n\r\n");
+        assert_eq!("GET", request.method);
+        assert_eq!("/", request.path);
+        assert_eq!(None, request.query);
+        assert_eq!("HTTP/1.1", request.version);
+        assert_eq!(Some("localhost:7878"), request.header("host"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn splits_the_query() {
+        let request = parse_ok(b"GET /books?author=tolkien&page=2 HTTP/1.0\r\n\r\n");
+        assert_eq!("/books", request.path);
+        assert_eq!(Some("author=tolkien&page=2".to_string()), request.query);
+
+        let request = parse_ok(b"GET /books? HTTP/1.1\r\n\r\n");
+        assert_eq!(Some(String::new()), request.query);
+    }
+
+    #[test]
+    fn reads_the_body_by_content_length() {
+        let raw = b"POST /books HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, and more";
+        let mut reader = Cursor::new(&raw[..]);
+        let request = read_request(&mut reader, &Limits::default()).unwrap().unwrap();
+        assert_eq!(b"hello", &request.body[..]);
+        assert_eq!(b", and more", &raw[reader.position() as usize..]);
+
+        let request = parse_ok(b"PUT /data HTTP/1.1\r\nContent-Length: 3\r\n\r\n\x00\xff\n");
+        assert_eq!(vec![0, 255, 10], request.body);
+    }
+
+    #[test]
+    fn reads_consecutive_requests() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET /c HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(&raw[..]);
+        let limits = Limits::default();
+        let paths = (0..3)
+            .map(|_| read_request(&mut reader, &limits).unwrap().unwrap().path)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["/a", "/b", "/c"], paths);
+        assert!(read_request(&mut reader, &limits).unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_headers_in_order_and_trims_values() {
+        let request = parse_ok(b"GET / HTTP/1.1\r\nAccept:  text/html \r\nX-Empty:\r\naccept: */*\r\n\r\n");
+        assert_eq!(
+            vec![
+                ("Accept".to_string(), "text/html".to_string()),
+                ("X-Empty".to_string(), String::new()),
+                ("accept".to_string(), "*/*".to_string()),
+            ],
+            request.headers
+        );
+        assert_eq!(Some("text/html"), request.header("ACCEPT"));
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds() {
+        let request = parse_ok(b"GET /lf HTTP/1.1\nHost: x\n\n");
+        assert_eq!("/lf", request.path);
+        assert_eq!(Some("x"), request.header("Host"));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(Some("/two words/ü".to_string()), percent_decode("/two%20words/%C3%bc"));
+        assert_eq!(Some("100%".to_string()), percent_decode("100%25"));
+        for broken in &["%", "%2", "%zz", "%+1", "%ff", "%é"] {
+            assert_eq!(None, percent_decode(broken), "{}", broken);
+        }
+    }
+
+    #[test]
+    fn returns_none_on_a_closed_connection() {
+        assert!(parse(b"").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        let cases: &[&[u8]] = &[
+            b"\r\n\r\n",
+            b"GET\r\n\r\n",
+            b"GET /\r\n\r\n",
+            b"GET  / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"G(T / HTTP/1.1\r\n\r\n",
+            b"GET books HTTP/1.1\r\n\r\n",
+            b"GET /a\x7fb HTTP/1.1\r\n\r\n",
+            b"GET / FTP/1.1\r\n\r\n",
+            b"GET / http/1.1\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ];
+        for case in cases {
+            assert!(malformed(case), "{:?}", String::from_utf8_lossy(case));
+        }
+        match parse(b"GET / HTTP/2.0\r\n\r\n") {
+            Err(ParseError::UnsupportedVersion) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let cases: &[&[u8]] = &[
+            b"GET / HTTP/1.1\r\nHost\r\n\r\n",
+            b"GET / HTTP/1.1\r\n: value\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX-Bad: a\x00b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n",
+            b"GET / HTTP/1.1\r\nHost: x",
+        ];
+        for case in cases {
+            assert!(malformed(case), "{:?}", String::from_utf8_lossy(case));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_content_lengths() {
+        let cases: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +1\r\n\r\nx",
+            b"POST / HTTP/1.1\r\nContent-Length: 1 2\r\n\r\nx",
+            b"POST / HTTP/1.1\r\nContent-Length:\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nxy",
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+        ];
+        for case in cases {
+            assert!(malformed(case), "{:?}", String::from_utf8_lossy(case));
+        }
+        // Repeating the same length is allowed.
+        let request = parse_ok(b"POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nok");
+        assert_eq!(b"ok", &request.body[..]);
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let raw = b"POST /books HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n7;name=value\r\n, world\r\n0\r\nExpires: never\r\n\r\n\
+                    GET /next HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(&raw[..]);
+        let request = read_request(&mut reader, &Limits::default()).unwrap().unwrap();
+        assert_eq!(b"hello, world", &request.body[..]);
+        assert_eq!(Some("chunked"), request.header("transfer-encoding"));
+        let next = read_request(&mut reader, &Limits::default()).unwrap().unwrap();
+        assert_eq!("/next", next.path);
+
+        let request = parse_ok(b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\nA\r\n0123456789\r\n0\r\n\r\n");
+        assert_eq!(b"0123456789", &request.body[..]);
+        let request = parse_ok(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n");
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        for raw in &[
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n-1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n11111111111111111\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nbad trailer\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n",
+        ] {
+            assert!(malformed(raw), "{}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[test]
+    fn rejects_other_transfer_encodings() {
+        for raw in &[
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        ] {
+            match parse(raw) {
+                Err(ParseError::UnsupportedTransferEncoding) => {}
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits {
+            max_header_bytes: 64,
+            max_headers: 2,
+            max_body_bytes: 4,
+        };
+        let read = |raw: &[u8]| read_request(&mut Cursor::new(raw), &limits);
+
+        let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        match read(long_path.as_bytes()) {
+            Err(ParseError::HeadersTooLarge) => {}
+            other => panic!("{:?}", other),
+        }
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(50));
+        match read(long_header.as_bytes()) {
+            Err(ParseError::HeadersTooLarge) => {}
+            other => panic!("{:?}", other),
+        }
+        match read(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n") {
+            Err(ParseError::TooManyHeaders) => {}
+            other => panic!("{:?}", other),
+        }
+        match read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello") {
+            Err(ParseError::BodyTooLarge) => {}
+            other => panic!("{:?}", other),
+        }
+        match read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n") {
+            Err(ParseError::BodyTooLarge) => {}
+            other => panic!("{:?}", other),
+        }
+
+        // Exactly at the limits is fine.
+        let exact = b"POST / HTTP/1.1\r\nA: 1\r\nContent-Length: 4\r\n\r\nbody";
+        let headers = exact.len() - 4;
+        let limits = Limits {
+            max_header_bytes: headers,
+            ..limits
+        };
+        let request = read_request(&mut Cursor::new(&exact[..]), &limits).unwrap().unwrap();
+        assert_eq!(b"body", &request.body[..]);
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        assert_eq!(Some((400, "Bad Request")), ParseError::Malformed("x").status());
+        assert_eq!(Some(431), ParseError::TooManyHeaders.status().map(|(code, _)| code));
+        assert_eq!(Some(413), ParseError::BodyTooLarge.status().map(|(code, _)| code));
+        let closed = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert_eq!(None, ParseError::Io(closed).status());
+    }
+
+    /// A small xorshift generator, so the fuzz tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+
+    const VALID: &[u8] = b"POST /books/1?x=y HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nhello world";
+
+    #[test]
+    fn survives_every_truncation() {
+        for end in 0..VALID.len() {
+            match parse(&VALID[..end]) {
+                Ok(None) => assert_eq!(0, end),
+                Ok(Some(request)) => panic!("parsed a truncated request: {:?}", request),
+                Err(_) => {}
+            }
+        }
+        assert!(parse(VALID).unwrap().is_some());
+    }
+
+    #[test]
+    fn survives_random_mutations() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let interesting = b"\r\n :?/\x00\xff0123456789-+";
+        for _ in 0..5000 {
+            let mut raw = VALID.to_vec();
+            for _ in 0..1 + rng.below(4) {
+                let index = rng.below(raw.len());
+                match rng.below(3) {
+                    0 => raw[index] = interesting[rng.below(interesting.len())],
+                    1 => {
+                        raw.remove(index);
+                    }
+                    _ => raw.insert(index, rng.next() as u8),
+                }
+            }
+            // Any outcome is fine as long as it is not a panic, and whatever
+            // parses keeps within the limits.
+            if let Ok(Some(request)) = parse(&raw) {
+                assert!(request.body.len() <= Limits::default().max_body_bytes);
+                assert!(is_token(&request.method));
+            }
+        }
+    }
+
+    #[test]
+    fn survives_random_bytes() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let limits = Limits {
+            max_header_bytes: 256,
+            max_headers: 4,
+            max_body_bytes: 16,
+        };
+        for _ in 0..5000 {
+            let raw = (0..rng.below(300)).map(|_| rng.next() as u8).collect::<Vec<_>>();
+            let _ = read_request(&mut Cursor::new(&raw[..]), &limits);
+        }
+    }
+}
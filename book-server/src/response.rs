@@ -0,0 +1,323 @@
+//! HTTP responses and how they go out on the wire.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// How much of a streamed body is held in memory at once.
+const STREAM_BUFFER: usize = 16 * 1024;
+
+/// What follows the headers.
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Read while the response is written, so that a large body never sits
+    /// in memory whole. With a known length it is sent with a
+    /// `Content-Length`; without one it is sent chunked.
+    Stream {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+}
+
+impl Body {
+    /// The length of the body, if known before it is sent.
+    pub fn len(&self) -> Option<u64> {
+        match *self {
+            Body::Bytes(ref bytes) => Some(bytes.len() as u64),
+            Body::Stream { length, .. } => length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Reads a streamed body to the end.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::Stream { mut reader, .. } => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Body::Bytes(ref bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Stream { length, .. } => f.debug_struct("Stream").field("length", &length).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// Headers other than `Content-Length` and `Transfer-Encoding`, which are
+    /// always derived from the body when the response is written.
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// A response with a plain text body.
+    pub fn text(status: u16, text: &str) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(text)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// A body read from `reader` as it is sent. `length` must be exactly
+    /// what `reader` yields, or `None` if that is not known up front.
+    pub fn with_stream<R: Read + Send + 'static>(mut self, reader: R, length: Option<u64>) -> Response {
+        self.body = Body::Stream {
+            reader: Box::new(reader),
+            length,
+        };
+        self
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the connection ends after this response, which is then also
+    /// what ends a streamed body of unknown length.
+    pub fn closes_connection(&self) -> bool {
+        self.header("Connection")
+            .is_some_and(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case("close")))
+    }
+
+    /// Writes the response as HTTP/1.1 and returns how many body bytes were
+    /// sent. Without `include_body`, as for a HEAD request, the headers still
+    /// describe the body that was left out.
+    ///
+    /// A streamed body of unknown length is sent chunked, unless the
+    /// response closes the connection; HTTP/1.0 clients need the latter.
+    pub fn write_to<W: Write>(self, writer: &mut W, include_body: bool) -> io::Result<u64> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding") {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        // 1xx, 204 and 304 responses never have a body.
+        let has_body = !(self.status < 200 || self.status == 204 || self.status == 304);
+        let chunked = has_body && self.body.len().is_none() && !self.closes_connection();
+        if has_body {
+            match self.body.len() {
+                Some(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+                None if chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        let sent = if !(include_body && has_body) {
+            0
+        } else {
+            match self.body {
+                Body::Bytes(bytes) => {
+                    writer.write_all(&bytes)?;
+                    bytes.len() as u64
+                }
+                Body::Stream { reader, length: Some(length) } => {
+                    let sent = copy(&mut reader.take(length), writer)?;
+                    // The head promised more; the connection cannot be reused.
+                    if sent < length {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the body ended early"));
+                    }
+                    sent
+                }
+                Body::Stream { mut reader, .. } if chunked => write_chunked(&mut reader, writer)?,
+                Body::Stream { mut reader, .. } => copy(&mut reader, writer)?,
+            }
+        };
+        writer.flush()?;
+        Ok(sent)
+    }
+}
+
+/// `io::copy` through a buffer of `STREAM_BUFFER` bytes.
+pub(crate) fn copy<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buffer = vec![0; STREAM_BUFFER];
+    let mut sent = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(sent),
+            Ok(read) => read,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        writer.write_all(&buffer[..read])?;
+        sent += read as u64;
+    }
+}
+
+/// Sends what `reader` yields as one chunk per read, then the last chunk.
+pub(crate) fn write_chunked<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buffer = vec![0; STREAM_BUFFER];
+    let mut sent = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        write!(writer, "{:x}\r\n", read)?;
+        writer.write_all(&buffer[..read])?;
+        writer.write_all(b"\r\n")?;
+        sent += read as u64;
+    }
+    writer.write_all(b"0\r\n\r\n")?;
+    Ok(sent)
+}
+
+/// The standard reason phrase for `status`.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn written(response: Response, include_body: bool) -> Vec<u8> {
+        let mut raw = Vec::new();
+        response.write_to(&mut raw, include_body).unwrap();
+        raw
+    }
+
+    /// A reader that hands out at most `step` bytes per read, like a pipe.
+    struct Trickle(Cursor<Vec<u8>>, usize);
+
+    impl Read for Trickle {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let step = self.1.min(buffer.len());
+            self.0.read(&mut buffer[..step])
+        }
+    }
+
+    #[test]
+    fn writes_status_headers_and_length() {
+        let response = Response::new(200)
+            .with_header("Content-Type", "application/octet-stream")
+            .with_body(vec![0, 159, 146, 150]);
+        let mut expected = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 4\r\n\r\n".to_vec();
+        expected.extend_from_slice(&[0, 159, 146, 150]);
+        assert_eq!(expected, written(response, true));
+    }
+
+    #[test]
+    fn head_responses_keep_the_length_but_not_the_body() {
+        let response = Response::text(404, "missing");
+        let raw = String::from_utf8(written(response, false)).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(raw.ends_with("Content-Length: 7\r\n\r\n"));
+    }
+
+    #[test]
+    fn derives_the_length_itself() {
+        let response = Response::new(304)
+            .with_header("Content-Length", "99")
+            .with_header("ETag", "\"x\"");
+        assert_eq!(Some("\"x\""), response.header("etag"));
+        assert_eq!(
+            b"HTTP/1.1 304 Not Modified\r\nETag: \"x\"\r\n\r\n".to_vec(),
+            written(response, true)
+        );
+    }
+
+    #[test]
+    fn streams_bodies_of_known_length() {
+        let body = "x".repeat(3 * STREAM_BUFFER + 7);
+        let response = Response::new(200).with_stream(Cursor::new(body.clone().into_bytes()), Some(body.len() as u64));
+        let mut raw = Vec::new();
+        assert_eq!(body.len() as u64, response.write_to(&mut raw, true).unwrap());
+        let raw = String::from_utf8(raw).unwrap();
+        assert_eq!(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body), raw);
+
+        // A reader that ends early is an error, as the length was a promise.
+        let response = Response::new(200).with_stream(Cursor::new(b"short".to_vec()), Some(10));
+        assert!(response.write_to(&mut Vec::new(), true).is_err());
+    }
+
+    #[test]
+    fn chunks_bodies_of_unknown_length() {
+        let reader = Trickle(Cursor::new(b"hello, world".to_vec()), 5);
+        let response = Response::new(200).with_header("Transfer-Encoding", "gzip").with_stream(reader, None);
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n5\r\n, wor\r\n2\r\nld\r\n0\r\n\r\n"
+                .to_vec(),
+            written(response, true)
+        );
+
+        let response = Response::new(200).with_stream(Cursor::new(b"unsent".to_vec()), None);
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec(),
+            written(response, false)
+        );
+    }
+
+    #[test]
+    fn closing_the_connection_ends_bodies_of_unknown_length() {
+        let response = Response::new(200)
+            .with_header("Connection", "close")
+            .with_stream(Cursor::new(b"until the end".to_vec()), None);
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nuntil the end".to_vec(),
+            written(response, true)
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Dispatching requests to handlers by method and path.
+
+use std::iter;
+
+use request::{percent_decode, Request};
+use response::Response;
+
+/// What a route runs. Handlers are shared by every worker thread.
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync>;
+
+/// The values a route pattern captured from the path, already decoded.
+#[derive(Debug, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    /// `:name`, one non-empty segment.
+    Param(String),
+    /// `*name`, the rest of the path, possibly empty.
+    Rest(String),
+}
+
+struct Route {
+    method: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+/// Routes requests to the first handler registered for their method and a
+/// matching path pattern.
+///
+/// Patterns are paths whose segments may be `:name`, matching any one
+/// segment, or a final `*name`, matching the rest of the path:
+///
+/// ```
+/// use bookserver::{Response, Router};
+///
+/// let router = Router::new()
+///     .get("/books/:id", |_, params| {
+///         Response::text(200, &format!("Book {}", params.get("id").unwrap()))
+///     })
+///     .get("/static/*path", |_, params| {
+///         Response::text(200, params.get("path").unwrap())
+///     });
+/// ```
+///
+/// A path that matches only routes for other methods is answered with 405,
+/// and one that matches nothing goes to the fallback, 404 by default. HEAD
+/// requests are served by GET routes.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Handler>,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Registers `handler` for `method` requests matching `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` does not start with `/`, names a parameter with
+    /// an empty name, or has a `*` segment before its last one.
+    pub fn route<F>(mut self, method: &str, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Handles the requests no route matches, such as by serving files.
+    pub fn fallback<F>(mut self, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let params = match match_path(&route.segments, &request.path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if route.method == request.method || (route.method == "GET" && request.method == "HEAD") {
+                return (route.handler)(request, &params);
+            }
+            allowed.push(route.method.as_str());
+        }
+
+        if allowed.is_empty() {
+            return match self.fallback {
+                Some(ref fallback) => fallback(request, &Params::default()),
+                None => Response::text(404, "Not found\n"),
+            };
+        }
+        if allowed.contains(&"GET") {
+            allowed.push("HEAD");
+        }
+        allowed.sort();
+        allowed.dedup();
+        Response::text(405, "Method not allowed\n").with_header("Allow", &allowed.join(", "))
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(pattern.starts_with('/'), "route pattern {:?} must start with '/'", pattern);
+    let parts = pattern[1..].split('/').collect::<Vec<_>>();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(index, part)| {
+            if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "route pattern {:?} has an unnamed parameter", pattern);
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "route pattern {:?} has an unnamed parameter", pattern);
+                assert!(index == parts.len() - 1, "route pattern {:?} has '*' before the end", pattern);
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            }
+        })
+        .collect()
+}
+
+fn match_path(segments: &[Segment], path: &str) -> Option<Params> {
+    let mut parts = path.strip_prefix('/')?.split('/');
+    let mut params = Vec::new();
+    for segment in segments {
+        match *segment {
+            Segment::Literal(ref literal) => {
+                if parts.next()? != literal {
+                    return None;
+                }
+            }
+            Segment::Param(ref name) => {
+                let part = parts.next().filter(|part| !part.is_empty())?;
+                params.push((name.clone(), percent_decode(part)?));
+            }
+            Segment::Rest(ref name) => {
+                // The slash before the rest is required, even if it ends there.
+                let first = parts.next()?;
+                let rest = iter::once(first).chain(parts.by_ref()).collect::<Vec<_>>().join("/");
+                params.push((name.clone(), percent_decode(&rest)?));
+            }
+        }
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(Params(params)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with the name of the route and the params it got.
+    fn named(name: &'static str) -> impl Fn(&Request, &Params) -> Response + Send + Sync {
+        move |_, params| {
+            let params = params
+                .0
+                .iter()
+                .map(|(name, value)| format!(" {}={}", name, value))
+                .collect::<String>();
+            Response::text(200, &format!("{}{}", name, params))
+        }
+    }
+
+    fn routed(router: &Router, method: &str, path: &str) -> (u16, String) {
+        let response = router.handle(&Request::test(method, path));
+        (response.status, String::from_utf8(response.body.into_bytes().unwrap()).unwrap())
+    }
+
+    fn books() -> Router {
+        Router::new()
+            .get("/", named("home"))
+            .get("/books", named("list"))
+            .post("/books", named("create"))
+            .get("/books/new", named("form"))
+            .get("/books/:id", named("show"))
+            .put("/books/:id", named("update"))
+            .delete("/books/:id", named("delete"))
+            .get("/authors/:author/books/:id", named("by-author"))
+            .get("/static/*path", named("static"))
+    }
+
+    #[test]
+    fn matches_literal_paths_by_method() {
+        let router = books();
+        assert_eq!((200, "home".to_string()), routed(&router, "GET", "/"));
+        assert_eq!((200, "list".to_string()), routed(&router, "GET", "/books"));
+        assert_eq!((200, "create".to_string()), routed(&router, "POST", "/books"));
+        assert_eq!(404, routed(&router, "GET", "/books/").0);
+        assert_eq!(404, routed(&router, "GET", "/book").0);
+        assert_eq!(404, routed(&router, "GET", "/books/1/pages").0);
+    }
+
+    #[test]
+    fn captures_path_params() {
+        let router = books();
+        assert_eq!((200, "show id=42".to_string()), routed(&router, "GET", "/books/42"));
+        assert_eq!((200, "update id=42".to_string()), routed(&router, "PUT", "/books/42"));
+        assert_eq!((200, "show id=war and peace".to_string()), routed(&router, "GET", "/books/war%20and%20peace"));
+        assert_eq!(
+            (200, "by-author author=tolkien id=7".to_string()),
+            routed(&router, "GET", "/authors/tolkien/books/7")
+        );
+        assert_eq!(404, routed(&router, "GET", "/books/%zz").0);
+    }
+
+    #[test]
+    fn prefers_routes_registered_first() {
+        let router = books();
+        assert_eq!((200, "form".to_string()), routed(&router, "GET", "/books/new"));
+    }
+
+    #[test]
+    fn captures_the_rest_of_the_path() {
+        let router = books();
+        assert_eq!((200, "static path=css/site.css".to_string()), routed(&router, "GET", "/static/css/site.css"));
+        assert_eq!((200, "static path=".to_string()), routed(&router, "GET", "/static/"));
+        assert_eq!(404, routed(&router, "GET", "/static").0);
+    }
+
+    #[test]
+    fn answers_other_methods_with_405() {
+        let router = books();
+        let response = router.handle(&Request::test("PATCH", "/books/1"));
+        assert_eq!(405, response.status);
+        assert_eq!(Some("DELETE, GET, HEAD, PUT"), response.header("Allow"));
+
+        let response = router.handle(&Request::test("DELETE", "/books"));
+        assert_eq!(Some("GET, HEAD, POST"), response.header("Allow"));
+    }
+
+    #[test]
+    fn serves_head_from_get_routes() {
+        assert_eq!((200, "show id=1".to_string()), routed(&books(), "HEAD", "/books/1"));
+    }
+
+    #[test]
+    fn falls_back_when_nothing_matches() {
+        let router = books().fallback(|request, _| Response::text(200, &format!("file {}", request.path)));
+        assert_eq!((200, "file /hello.html".to_string()), routed(&router, "GET", "/hello.html"));
+        // Known paths still get their 405.
+        assert_eq!(405, routed(&router, "POST", "/books/1").0);
+    }
+
+    #[test]
+    #[should_panic(expected = "must start with '/'")]
+    fn rejects_relative_patterns() {
+        Router::new().get("books", named("list"));
+    }
+
+    #[test]
+    #[should_panic(expected = "has '*' before the end")]
+    fn rejects_rest_params_before_the_end() {
+        Router::new().get("/files/*path/edit", named("edit"));
+    }
+}
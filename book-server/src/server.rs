@@ -0,0 +1,225 @@
+//! An HTTP server: a listener, a pool of workers and a router.
+
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use access_log::AccessLog;
+use compression::Compression;
+use connection::{serve_connection, Settings};
+use proxy::Proxy;
+use request::Limits;
+use router::Router;
+use ThreadPool;
+
+/// Serves the routes of a `Router`, one connection per worker thread.
+///
+/// ```no_run
+/// use bookserver::{Response, Router, Server};
+///
+/// let router = Router::new().get("/", |_, _| Response::text(200, "Hello!"));
+/// Server::new(router).threads(8).listen("127.0.0.1:7878").unwrap();
+/// ```
+pub struct Server {
+    router: Arc<Router>,
+    threads: usize,
+    settings: Settings,
+}
+
+impl Server {
+    pub fn new(router: Router) -> Server {
+        Server {
+            router: Arc::new(router),
+            threads: 4,
+            settings: Settings::default(),
+        }
+    }
+
+    /// How many connections are served at once. Defaults to 4.
+    pub fn threads(mut self, threads: usize) -> Server {
+        self.threads = threads;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// How long an idle connection is kept open. Defaults to
+    /// `DEFAULT_IDLE_TIMEOUT`.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Server {
+        self.settings.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// How long a client may take to send the request line and headers,
+    /// however steadily it trickles them in. Defaults to
+    /// `DEFAULT_HEADER_TIMEOUT`.
+    pub fn header_timeout(mut self, header_timeout: Duration) -> Server {
+        self.settings.header_timeout = header_timeout;
+        self
+    }
+
+    /// How long sending a response may stall. Defaults to
+    /// `DEFAULT_WRITE_TIMEOUT`.
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Server {
+        self.settings.write_timeout = write_timeout;
+        self
+    }
+
+    /// Logs every request to `access_log`. There is no access log by
+    /// default.
+    pub fn access_log(mut self, access_log: AccessLog) -> Server {
+        self.settings.access_log = Some(Arc::new(access_log));
+        self
+    }
+
+    /// Compresses responses for clients that accept it. Responses are sent
+    /// as they are by default.
+    pub fn compression(mut self, compression: Compression) -> Server {
+        self.settings.compression = Some(compression);
+        self
+    }
+
+    /// Forwards the requests under the prefixes of `proxy` upstream before
+    /// the router sees them, streaming their bodies. Nothing is proxied by
+    /// default.
+    pub fn proxy(mut self, proxy: Proxy) -> Server {
+        self.settings.proxy = Some(Arc::new(proxy));
+        self
+    }
+
+    /// Binds `address` and serves forever.
+    pub fn listen<A: ToSocketAddrs>(self, address: A) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        self.run(listener)
+    }
+
+    /// Serves connections from `listener` forever.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of threads is zero.
+    pub fn run(self, listener: TcpListener) -> io::Result<()> {
+        let pool = ThreadPool::new(self.threads);
+        let settings = Arc::new(self.settings);
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // Such as running out of file descriptors; the next one may
+                // work again.
+                Err(error) => {
+                    warn!("Failed to accept a connection: {}", error);
+                    continue;
+                }
+            };
+            let router = Arc::clone(&self.router);
+            let settings = Arc::clone(&settings);
+
+            pool.execute(move || {
+                let result = serve_connection(stream, &settings, |request| router.handle(request));
+                if let Err(error) = result {
+                    debug!("Connection failed: {}", error);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    use response::Response;
+
+    fn start(router: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || Server::new(router).threads(2).run(listener));
+        address
+    }
+
+    fn send(address: SocketAddr, raw: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn routes_requests_to_handlers() {
+        let address = start(
+            Router::new()
+                .get("/books/:id", |_, params| {
+                    Response::text(200, &format!("Book {}", params.get("id").unwrap()))
+                })
+                .post("/books", |request, _| {
+                    Response::new(201)
+                        .with_header("Location", "/books/3")
+                        .with_body(request.body.clone())
+                }),
+        );
+
+        let response = send(address, "GET /books/12 HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nBook 12"), "{}", response);
+
+        let response = send(
+            address,
+            "POST /books HTTP/1.1\r\nContent-Length: 4\r\n\r\nDune\
+             DELETE /books HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert!(response.contains("Location: /books/3\r\n"));
+        assert!(response.contains("\r\n\r\nDuneHTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn streams_proxied_bodies_past_the_limits() {
+        let upstream = start(Router::new().post("/upload", |request, _| {
+            Response::text(200, &format!("{} bytes", request.body.len()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(Router::new().post("/local", |_, _| Response::text(200, "local")))
+            .limits(Limits {
+                max_body_bytes: 16,
+                ..Limits::default()
+            })
+            .proxy(Proxy::new().route("/upload", &upstream.to_string()));
+        thread::spawn(move || server.run(listener));
+
+        let body = "x".repeat(1000);
+        let response = send(
+            address,
+            &format!(
+                "POST /upload HTTP/1.1\r\nContent-Length: 1000\r\n\r\n{}\
+                 POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3e8\r\n{}\r\n0\r\n\r\n\
+                 POST /local HTTP/1.1\r\nContent-Length: 1000\r\n\r\n{}",
+                body, body, body
+            ),
+        );
+        assert_eq!(2, response.matches("\r\n\r\n1000 bytes").count(), "{}", response);
+        assert!(response.contains("1000 bytesHTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn serves_connections_concurrently() {
+        let address = start(Router::new().get("/", |_, _| Response::text(200, "ok")));
+
+        // An idle keep-alive connection holds one of the two workers.
+        let _idle = TcpStream::connect(address).unwrap();
+        let response = send(address, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    }
+}
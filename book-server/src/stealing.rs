@@ -0,0 +1,245 @@
+//! A thread pool where each worker has its own queue and steals from the
+//! others when it runs dry, instead of every worker waiting on one shared
+//! receiver.
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use Job;
+
+/// How long an idle worker sleeps before looking for work again, in case it
+/// missed a wake-up for a job another worker holds.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+/// How many times an idle worker yields and looks again before sleeping.
+const SPIN_ROUNDS: u32 = 32;
+
+struct Shared {
+    /// New jobs from `execute`, taken in batches by the workers.
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    shutdown: AtomicBool,
+    /// Workers waiting on `wake`, so that `execute` only pays for a wake-up
+    /// when someone is asleep.
+    sleeping: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn notify_one(&self) {
+        // Pairs with the fence in `work`: either the worker sees the new job
+        // or this sees the worker asleep.
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        // Taking the lock orders this after an idle worker's last check.
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_one();
+    }
+
+    fn notify_all(&self) {
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_all();
+    }
+
+    /// The next job for worker `id`: its own first, then new ones, then
+    /// whatever the other workers have queued.
+    fn find_job(&self, id: usize, local: &Deque<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector.steal_batch_and_pop(local).or_else(|| {
+                    self.stealers
+                        .iter()
+                        .enumerate()
+                        .filter(|&(other, _)| other != id)
+                        .map(|(_, stealer)| stealer.steal())
+                        .collect::<Steal<Job>>()
+                })
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+}
+
+/// A drop-in alternative to `ThreadPool` for many small jobs, where workers
+/// do not contend on a single lock.
+///
+/// Dropping the pool waits for every job submitted before.
+pub struct WorkStealingPool {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkStealingPool {
+    /// Create a new WorkStealingPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> WorkStealingPool {
+        assert!(size > 0);
+
+        let deques = (0..size).map(|_| Deque::new_fifo()).collect::<Vec<_>>();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: deques.iter().map(Deque::stealer).collect(),
+            shutdown: AtomicBool::new(false),
+            sleeping: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+        });
+
+        let threads = deques
+            .into_iter()
+            .enumerate()
+            .map(|(id, local)| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || work(id, &local, &shared))
+            })
+            .collect();
+
+        WorkStealingPool { shared, threads }
+    }
+
+    pub fn execute<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static
+    {
+        self.shared.injector.push(Box::new(f));
+        self.shared.notify_one();
+    }
+}
+
+impl Drop for WorkStealingPool {
+    fn drop(&mut self) {
+        debug!("Shutting down all work-stealing workers.");
+
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn work(id: usize, local: &Deque<Job>, shared: &Shared) {
+    let mut idle_rounds = 0;
+    loop {
+        if let Some(job) = shared.find_job(id, local) {
+            idle_rounds = 0;
+            // Let an idle worker steal the rest of a batch we took.
+            if !local.is_empty() {
+                shared.notify_one();
+            }
+            if panic::catch_unwind(AssertUnwindSafe(|| job.call_box())).is_err() {
+                warn!("Work-stealing worker {} recovered from a panicking job.", id);
+            }
+            continue;
+        }
+        // More jobs are often on their way; going to sleep and being woken
+        // costs far more than a few more looks.
+        if idle_rounds < SPIN_ROUNDS {
+            idle_rounds += 1;
+            thread::yield_now();
+            continue;
+        }
+
+        let guard = shared.sleep.lock().unwrap();
+        if shared.shutdown.load(Ordering::SeqCst) {
+            // No more jobs can arrive, and the other workers finish their
+            // own queues.
+            if shared.injector.is_empty() {
+                debug!("Work-stealing worker {} is done.", id);
+                return;
+            }
+        } else {
+            shared.sleeping.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            if shared.injector.is_empty() {
+                let _ = shared.wake.wait_timeout(guard, IDLE_POLL).unwrap();
+            }
+            shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    #[test]
+    fn runs_every_job_before_dropping() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let pool = WorkStealingPool::new(4);
+        for _ in 0..10_000 {
+            let completed = Arc::clone(&completed);
+            pool.execute(move || {
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        drop(pool);
+
+        assert_eq!(10_000, completed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn spreads_long_jobs_over_the_workers() {
+        let pool = WorkStealingPool::new(4);
+        let (done, finished) = mpsc::channel();
+        let started = Instant::now();
+        for _ in 0..8 {
+            let done = done.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(100));
+                done.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..8 {
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        // Two rounds of four; one worker doing them all would take 800ms.
+        assert!(started.elapsed() < Duration::from_millis(600), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let pool = WorkStealingPool::new(1);
+        pool.execute(|| panic!("job failed"));
+        let counter = Arc::clone(&completed);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        drop(pool);
+
+        assert_eq!(1, completed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn wakes_idle_workers_for_new_jobs() {
+        let pool = WorkStealingPool::new(2);
+        // Let both workers go to sleep first.
+        thread::sleep(Duration::from_millis(20));
+
+        let (done, finished) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap());
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
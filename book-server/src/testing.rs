@@ -0,0 +1,23 @@
+//! Helpers shared by the tests of several modules.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DIRECTORY: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh, empty directory under the system's temporary one, unique to
+/// this process and call.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!(
+        "bookserver-{}-{}-{}",
+        name,
+        process::id(),
+        NEXT_DIRECTORY.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
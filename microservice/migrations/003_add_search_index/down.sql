@@ -0,0 +1 @@
+DROP INDEX messages_search_idx;
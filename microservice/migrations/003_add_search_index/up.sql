@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS messages_search_idx ON messages
+  USING GIN (to_tsvector('english', username || ' ' || message));
@@ -0,0 +1,7 @@
+DROP TABLE message_history;
+
+ALTER TABLE users DROP COLUMN is_admin;
+
+ALTER TABLE messages
+  DROP COLUMN edited_at,
+  DROP COLUMN deleted_at;
@@ -0,0 +1,16 @@
+ALTER TABLE messages
+  ADD COLUMN edited_at BIGINT,
+  ADD COLUMN deleted_at BIGINT;
+
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE message_history (
+  id SERIAL PRIMARY KEY,
+  message_id INTEGER NOT NULL REFERENCES messages (id),
+  action VARCHAR(16) NOT NULL,
+  message TEXT NOT NULL,
+  edited_by VARCHAR(128) NOT NULL,
+  edited_at BIGINT NOT NULL
+);
+
+CREATE INDEX message_history_message_id_idx ON message_history (message_id);
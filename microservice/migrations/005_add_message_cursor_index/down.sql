@@ -0,0 +1 @@
+DROP INDEX messages_timestamp_id_idx;
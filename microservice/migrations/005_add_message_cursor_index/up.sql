@@ -0,0 +1,2 @@
+-- Keyset pagination reads messages in (timestamp, id) order.
+CREATE INDEX messages_timestamp_id_idx ON messages (timestamp, id);
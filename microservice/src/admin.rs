@@ -0,0 +1,109 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use auth;
+use config::Config;
+use migrations;
+use store::{self, PgStore, StoreError, UserStore};
+
+pub fn keys_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("keys")
+        .about("Manages API keys, in the configured database")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Mints a new key for a user, creating the user if needed")
+                .arg(Arg::with_name("username").required(true))
+                .arg(
+                    Arg::with_name("admin")
+                        .long("admin")
+                        .help("Lets the user edit and delete every message"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("revoke")
+                .about("Revokes a key by its id")
+                .arg(Arg::with_name("id").required(true)),
+        )
+}
+
+pub fn run_keys(matches: &ArgMatches, config: &Config) -> Result<(), StoreError> {
+    // Keys minted into an in-memory store would be gone on exit.
+    let database_url = config
+        .database_url
+        .as_ref()
+        .ok_or_else(|| StoreError::from("A database URL must be configured to manage keys"))?;
+    let store = PgStore::connect(database_url, 1)?;
+
+    match matches.subcommand() {
+        ("create", Some(matches)) => {
+            let username = matches.value_of("username").unwrap();
+            let key = auth::generate_key();
+            let api_key = store.create_key(username, &auth::hash_key(&key))?;
+            if matches.is_present("admin") {
+                store.set_admin(username, true)?;
+            }
+            println!("Created key {} for {}: {}", api_key.id, username, key);
+            Ok(())
+        }
+        ("revoke", Some(matches)) => {
+            let id = matches
+                .value_of("id")
+                .unwrap()
+                .parse::<i32>()
+                .map_err(|error| StoreError::from(format!("Error parsing 'id': {}", error)))?;
+            if store.revoke_key(id)? {
+                println!("Revoked key {}", id);
+                Ok(())
+            } else {
+                Err(StoreError::from(format!("No key with id {}", id)))
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn migrate_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("migrate")
+        .about("Migrates the configured database, applying every pending migration by default")
+        .subcommand(SubCommand::with_name("up").about("Applies every pending migration"))
+        .subcommand(SubCommand::with_name("down").about("Reverts the latest applied migration"))
+        .subcommand(SubCommand::with_name("status").about("Lists migrations and whether they are applied"))
+}
+
+pub fn run_migrate(matches: &ArgMatches, config: &Config) -> Result<(), StoreError> {
+    let database_url = config
+        .database_url
+        .as_ref()
+        .ok_or_else(|| StoreError::from("A database URL must be configured to run migrations"))?;
+    let connection = store::establish(database_url)?;
+
+    match matches.subcommand_name() {
+        Some("up") | None => {
+            let applied = migrations::run_pending(&connection)?;
+            if applied.is_empty() {
+                println!("The database is up to date");
+            }
+            for migration in applied {
+                println!("Applied {}", migration.name);
+            }
+        }
+        Some("down") => match migrations::revert_latest(&connection)? {
+            Some(migration) => println!("Reverted {}", migration.name),
+            None => println!("No migrations to revert"),
+        },
+        Some("status") => {
+            let applied = migrations::applied_versions(&connection)?;
+            for migration in migrations::MIGRATIONS {
+                let state = if applied.contains(&migration.version) { "applied" } else { "pending" };
+                println!("{} {}", migration.name, state);
+            }
+            for version in applied.iter().filter(|&&version| {
+                migrations::MIGRATIONS.iter().all(|migration| migration.version != version)
+            }) {
+                println!("{:03} unknown", version);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use hex;
+use rand::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use hyper::Headers;
+use hyper::header::{Authorization, Bearer};
+
+use models::User;
+use store::{StoreError, UserStore};
+
+header! { (XApiKey, "X-Api-Key") => [String] }
+
+/// The username anonymous posts are stored under. No key is ever issued
+/// for it, so no one can pass for their author.
+pub const ANONYMOUS: &str = "anonymous";
+
+pub struct AuthConfig {
+    /// Whether posts without credentials are accepted (as "anonymous").
+    pub allow_anonymous: bool,
+}
+
+pub enum AuthError {
+    MissingCredentials,
+    InvalidCredentials,
+    Store(StoreError),
+}
+
+/// Resolves the `Authorization: Bearer` or `X-Api-Key` credentials of a
+/// request to a user. `Ok(None)` means an anonymous request that the config
+/// allows.
+pub fn authenticate(
+    headers: &Headers,
+    users: &dyn UserStore,
+    config: &AuthConfig,
+) -> Result<Option<User>, AuthError> {
+    let key = headers
+        .get::<Authorization<Bearer>>()
+        .map(|bearer| bearer.token.clone())
+        .or_else(|| headers.get::<XApiKey>().map(|key| key.0.clone()));
+
+    match key {
+        Some(key) => match users.find_user_by_key(&hash_key(&key)) {
+            Ok(Some(user)) => Ok(Some(user)),
+            Ok(None) => Err(AuthError::InvalidCredentials),
+            Err(error) => Err(AuthError::Store(error)),
+        },
+        None if config.allow_anonymous => Ok(None),
+        None => Err(AuthError::MissingCredentials),
+    }
+}
+
+/// Creates a random 256-bit key, hex encoded.
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng::new()
+        .expect("Error opening the OS random number generator")
+        .fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
@@ -0,0 +1,256 @@
+use toml;
+use url::Url;
+
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use logging::LogFormat;
+
+/// Everything the server reads at startup. Each setting comes from the
+/// defaults, then the config file, then the environment, the last one found
+/// winning.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where to listen, `BIND_ADDRESS`.
+    pub address: SocketAddr,
+    /// The Postgres database, `DATABASE_URL`. Without one messages are only
+    /// kept in memory.
+    pub database_url: Option<String>,
+    /// Most database connections open at once, `POOL_SIZE`.
+    pub pool_size: u32,
+    /// Whether to apply pending migrations at startup, `AUTO_MIGRATE`.
+    pub auto_migrate: bool,
+    /// Whether posts without credentials are accepted (as "anonymous"),
+    /// `ALLOW_ANONYMOUS`.
+    pub allow_anonymous: bool,
+    /// `text` or `json`, `LOG_FORMAT`.
+    pub log_format: LogFormat,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest request body in bytes, `MAX_BODY_SIZE`.
+    pub max_body_size: usize,
+    /// Writes per minute from one address, `RATE_LIMIT_PER_IP`; 0 disables
+    /// the limit.
+    pub rate_limit_per_ip: u32,
+    /// Writes per minute by one user, `RATE_LIMIT_PER_USER`; 0 disables the
+    /// limit.
+    pub rate_limit_per_user: u32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: "127.0.0.1:8080".parse().unwrap(),
+            database_url: None,
+            pool_size: 10,
+            auto_migrate: true,
+            allow_anonymous: true,
+            log_format: LogFormat::Text,
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_body_size: 64 * 1024,
+            rate_limit_per_ip: 60,
+            rate_limit_per_user: 30,
+        }
+    }
+}
+
+impl Config {
+    /// Layers the TOML file at `path`, if any, and the environment over the
+    /// defaults, and validates the result.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|error| format!("Error reading '{}': {}", path, error))?;
+                Config::parse(&text).map_err(|error| format!("Error parsing '{}': {}", path, error))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    fn apply_env<F>(&mut self, var: F) -> Result<(), String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        override_with(&var, "BIND_ADDRESS", &mut self.address)?;
+        if let Some(database_url) = var("DATABASE_URL") {
+            self.database_url = Some(database_url);
+        }
+        override_with(&var, "POOL_SIZE", &mut self.pool_size)?;
+        override_flag(&var, "AUTO_MIGRATE", &mut self.auto_migrate)?;
+        override_flag(&var, "ALLOW_ANONYMOUS", &mut self.allow_anonymous)?;
+        override_with(&var, "LOG_FORMAT", &mut self.log_format)?;
+        override_with(&var, "MAX_BODY_SIZE", &mut self.limits.max_body_size)?;
+        override_with(&var, "RATE_LIMIT_PER_IP", &mut self.limits.rate_limit_per_ip)?;
+        override_with(&var, "RATE_LIMIT_PER_USER", &mut self.limits.rate_limit_per_user)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pool_size == 0 {
+            return Err(String::from("'pool_size' must be at least 1"));
+        }
+        if self.limits.max_body_size == 0 {
+            return Err(String::from("'max_body_size' must be at least 1"));
+        }
+        if let Some(ref database_url) = self.database_url {
+            match Url::parse(database_url) {
+                Ok(ref url) if url.scheme() == "postgres" || url.scheme() == "postgresql" => {}
+                Ok(_) => return Err(String::from("'database_url' must be a postgres:// URL")),
+                Err(error) => return Err(format!("Error parsing 'database_url': {}", error)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The effective config as TOML, with the database password hidden.
+    pub fn redacted(&self) -> String {
+        let mut config = toml::Value::try_from(self).unwrap();
+        if let Some(ref database_url) = self.database_url {
+            let redacted = match Url::parse(database_url) {
+                Ok(ref mut url) if url.password().is_some() => {
+                    url.set_password(Some("REDACTED")).unwrap();
+                    url.to_string()
+                }
+                _ => database_url.clone(),
+            };
+            config["database_url"] = toml::Value::String(redacted);
+        }
+        config.to_string()
+    }
+}
+
+fn override_with<F, T>(var: &F, name: &str, value: &mut T) -> Result<(), String>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(text) = var(name) {
+        *value = text
+            .parse()
+            .map_err(|error| format!("Error parsing '{}': {}", name, error))?;
+    }
+    Ok(())
+}
+
+/// Flags are `true`, `false`, `1` or `0`, in any case. Anything else is an
+/// error rather than a guess, since a flag may open up the service.
+fn override_flag<F>(var: &F, name: &str, value: &mut bool) -> Result<(), String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(text) = var(name) {
+        *value = match text.to_ascii_lowercase().as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => return Err(format!("Error parsing '{}': expected true, false, 1 or 0", name)),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn layers_file_then_environment() {
+        let mut config = Config::parse(
+            "address = \"0.0.0.0:9000\"\n\
+             pool_size = 4\n\
+             [limits]\n\
+             rate_limit_per_ip = 10\n",
+        ).unwrap();
+        assert_eq!(4, config.pool_size);
+        assert_eq!(10, config.limits.rate_limit_per_ip);
+        assert_eq!(30, config.limits.rate_limit_per_user);
+
+        let vars = env(&[("POOL_SIZE", "8"), ("LOG_FORMAT", "json"), ("AUTO_MIGRATE", "0")]);
+        config.apply_env(|name| vars.get(name).cloned()).unwrap();
+        assert_eq!("0.0.0.0:9000".parse::<SocketAddr>().unwrap(), config.address);
+        assert_eq!(8, config.pool_size);
+        assert_eq!(LogFormat::Json, config.log_format);
+        assert!(!config.auto_migrate);
+        assert!(config.allow_anonymous);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Config::parse("pool_size = \"many\"").is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+
+        let vars = env(&[("BIND_ADDRESS", "localhost")]);
+        let error = Config::default()
+            .apply_env(|name| vars.get(name).cloned())
+            .unwrap_err();
+        assert!(error.starts_with("Error parsing 'BIND_ADDRESS'"));
+
+        for flag in &["no", "off", "yes", "ture"] {
+            let vars = env(&[("ALLOW_ANONYMOUS", flag)]);
+            let error = Config::default()
+                .apply_env(|name| vars.get(name).cloned())
+                .unwrap_err();
+            assert!(error.starts_with("Error parsing 'ALLOW_ANONYMOUS'"), "{}", flag);
+        }
+        let vars = env(&[("ALLOW_ANONYMOUS", "False"), ("AUTO_MIGRATE", "TRUE")]);
+        let mut config = Config::default();
+        config.apply_env(|name| vars.get(name).cloned()).unwrap();
+        assert!(!config.allow_anonymous);
+        assert!(config.auto_migrate);
+
+        let config = Config {
+            pool_size: 0,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            database_url: Some(String::from("mysql://localhost/microservice")),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn redacts_the_database_password() {
+        let config = Config {
+            database_url: Some(String::from("postgres://app:hunter2@db:5432/microservice")),
+            ..Config::default()
+        };
+        let printed = config.redacted();
+        assert!(!printed.contains("hunter2"));
+        assert!(printed.contains("postgres://app:REDACTED@db:5432/microservice"));
+        assert!(Config::parse(&printed).is_ok());
+    }
+}
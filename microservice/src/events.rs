@@ -0,0 +1,43 @@
+use futures::sync::mpsc;
+
+use std::sync::Mutex;
+
+use models::Message;
+use pagination::{Cursor, Direction};
+
+/// Fans newly persisted messages out to every open `/api/stream` connection.
+#[derive(Default)]
+pub struct Broadcaster {
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Message>>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        Broadcaster::default()
+    }
+
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Message> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends `message` to all subscribers, forgetting the ones that went away.
+    pub fn publish(&self, message: &Message) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+    }
+}
+
+/// Formats a message as a Server-Sent Event. The event id is a cursor at the
+/// message, so that clients resuming with `Last-Event-ID` neither miss nor
+/// repeat messages posted in the same second.
+pub fn format_event(message: &Message) -> String {
+    format!(
+        "id: {}\nevent: message\ndata: {}\n\n",
+        Cursor::at(message, Direction::Forward).encode(),
+        message.to_json()
+    )
+}
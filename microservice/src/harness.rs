@@ -0,0 +1,244 @@
+//! Runs the service in-process on an ephemeral port, against an in-memory
+//! store, and talks to it over real sockets.
+
+use serde_json::{self, Value};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use url::form_urlencoded;
+
+use std::io::{Read, Write};
+use std::net::{self, SocketAddr, TcpStream};
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use accept;
+use auth;
+use config::Config;
+use store::{MemoryStore, Store};
+
+pub struct TestServer {
+    address: SocketAddr,
+    store: Arc<dyn Store>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::with_config(Config::default())
+    }
+
+    /// Serves from a background thread that lives as long as the test binary.
+    pub fn with_config(config: Config) -> TestServer {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server_store = Arc::clone(&store);
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let listener = TcpListener::from_listener(listener, &address, &handle).unwrap();
+            core.run(accept(listener, server_store, &config, &handle)).unwrap();
+        });
+        TestServer { address, store }
+    }
+
+    /// Mints an API key straight into the store.
+    pub fn create_key(&self, username: &str, admin: bool) -> String {
+        let key = auth::generate_key();
+        self.store.create_key(username, &auth::hash_key(&key)).unwrap();
+        if admin {
+            self.store.set_admin(username, true).unwrap();
+        }
+        key
+    }
+
+    pub fn get(&self, path: &str) -> TestRequest {
+        self.request("GET", path)
+    }
+
+    pub fn post(&self, path: &str) -> TestRequest {
+        self.request("POST", path)
+    }
+
+    pub fn patch(&self, path: &str) -> TestRequest {
+        self.request("PATCH", path)
+    }
+
+    pub fn delete(&self, path: &str) -> TestRequest {
+        self.request("DELETE", path)
+    }
+
+    pub fn request(&self, method: &str, path: &str) -> TestRequest {
+        TestRequest {
+            address: self.address,
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Posts `message` anonymously and returns its timestamp.
+    pub fn post_message(&self, message: &str) -> i64 {
+        let response = self.post("/api").form(&[("message", message)]).send();
+        assert_eq!(200, response.status, "{}", response.text());
+        response.json()["timestamp"].as_i64().unwrap()
+    }
+}
+
+pub struct TestRequest {
+    address: SocketAddr,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl TestRequest {
+    pub fn header(mut self, name: &str, value: &str) -> TestRequest {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn bearer(self, key: &str) -> TestRequest {
+        self.header("Authorization", &format!("Bearer {}", key))
+    }
+
+    pub fn form(self, fields: &[(&str, &str)]) -> TestRequest {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish();
+        self.header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body.as_bytes())
+    }
+
+    pub fn json(self, value: &Value) -> TestRequest {
+        self.header("Content-Type", "application/json")
+            .body(value.to_string().as_bytes())
+    }
+
+    pub fn body(mut self, body: &[u8]) -> TestRequest {
+        self.body = body.to_vec();
+        self
+    }
+
+    pub fn send(self) -> TestResponse {
+        let mut stream = self.connect();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).unwrap();
+        TestResponse::parse(&raw)
+    }
+
+    /// Reads a response that never ends, such as an event stream, until its
+    /// raw text contains `pattern` `count` times. `then` runs once the
+    /// headers have arrived.
+    pub fn read_stream<F: FnOnce()>(self, pattern: &str, count: usize, then: F) -> String {
+        let mut stream = self.connect();
+        let mut raw = Vec::new();
+        let mut then = Some(then);
+        let mut buffer = [0; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&raw).into_owned();
+            if then.is_some() && text.contains("\r\n\r\n") {
+                then.take().unwrap()();
+            }
+            if text.matches(pattern).count() >= count {
+                return text;
+            }
+            let read = stream.read(&mut buffer).unwrap();
+            assert!(read > 0, "stream closed early: {}", text);
+            raw.extend_from_slice(&buffer[..read]);
+        }
+    }
+
+    fn connect(self) -> TcpStream {
+        let mut stream = TcpStream::connect(self.address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            self.method,
+            self.path,
+            self.address,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&self.body).unwrap();
+        stream
+    }
+}
+
+pub struct TestResponse {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    fn parse(raw: &[u8]) -> TestResponse {
+        let split = raw
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("no end of headers");
+        let head = str::from_utf8(&raw[..split]).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_at(line.find(':').unwrap());
+                (name.to_string(), value[1..].trim().to_string())
+            })
+            .collect::<Vec<_>>();
+
+        let mut response = TestResponse {
+            status,
+            headers,
+            body: raw[split + 4..].to_vec(),
+        };
+        if response.header("Transfer-Encoding") == Some("chunked") {
+            response.body = decode_chunked(&response.body);
+        }
+        response
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8(self.body.clone()).unwrap()
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|error| {
+            panic!("invalid JSON ({}): {}", error, self.text())
+        })
+    }
+
+    /// The `"error"` of a JSON error response.
+    pub fn error(&self) -> String {
+        self.json()["error"].as_str().unwrap().to_string()
+    }
+}
+
+fn decode_chunked(mut raw: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw.windows(2).position(|window| window == b"\r\n").unwrap();
+        let size = usize::from_str_radix(str::from_utf8(&raw[..line_end]).unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return body;
+        }
+        let start = line_end + 2;
+        body.extend_from_slice(&raw[start..start + size]);
+        raw = &raw[start + size + 2..];
+    }
+}
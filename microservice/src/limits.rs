@@ -0,0 +1,205 @@
+use futures::{future, Future, Stream};
+use hyper::{self, Chunk};
+use hyper::header::ContentLength;
+use hyper::server::Request;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use config::LimitsConfig;
+
+pub struct Limits {
+    pub max_body_size: usize,
+    pub per_ip: Option<Box<dyn RateLimiter>>,
+    pub per_user: Option<Box<dyn RateLimiter>>,
+}
+
+impl Limits {
+    /// In-memory limiters for the configured rates, in requests per minute
+    /// where 0 disables the limit.
+    pub fn new(config: &LimitsConfig) -> Limits {
+        Limits {
+            max_body_size: config.max_body_size,
+            per_ip: per_minute(config.rate_limit_per_ip),
+            per_user: per_minute(config.rate_limit_per_user),
+        }
+    }
+}
+
+fn per_minute(requests: u32) -> Option<Box<dyn RateLimiter>> {
+    if requests == 0 {
+        None
+    } else {
+        Some(Box::new(MemoryRateLimiter::new(requests, Duration::from_secs(60))))
+    }
+}
+
+/// Decides whether a client, identified by an arbitrary key, may make another
+/// request.
+pub trait RateLimiter: Send + Sync {
+    /// Counts a request for `key`, or returns how long the client should wait
+    /// when it is over its limit.
+    fn check(&self, key: &str) -> Result<(), Duration>;
+}
+
+/// A token bucket per key: `limit` requests at once, refilled evenly over
+/// `period`.
+pub struct MemoryRateLimiter {
+    limit: u32,
+    period: Duration,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl MemoryRateLimiter {
+    pub fn new(limit: u32, period: Duration) -> MemoryRateLimiter {
+        MemoryRateLimiter {
+            limit,
+            period,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let limit = f64::from(self.limit);
+        let per_second = limit / self.period.as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Buckets that have refilled completely carry no information.
+        if buckets.len() > 1024 {
+            let period = self.period;
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < period);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(limit);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+impl RateLimiter for MemoryRateLimiter {
+    fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+}
+
+#[derive(Debug)]
+pub struct PayloadTooLarge;
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request body too large")
+    }
+}
+
+impl Error for PayloadTooLarge {}
+
+/// Buffers a request body, failing with `PayloadTooLarge` up front when the
+/// declared `Content-Length` is over `max_size` bytes, or as soon as the body
+/// grows past it otherwise.
+pub fn read_body(request: Request, max_size: usize) -> Box<dyn Future<Item = Chunk, Error = hyper::Error>> {
+    if let Some(&ContentLength(length)) = request.headers().get::<ContentLength>() {
+        if length > max_size as u64 {
+            return Box::new(future::err(too_large()));
+        }
+    }
+    let future = request
+        .body()
+        .fold(Vec::new(), move |mut buffer, chunk| {
+            if buffer.len() + chunk.len() > max_size {
+                Err(too_large())
+            } else {
+                buffer.extend_from_slice(&chunk);
+                Ok(buffer)
+            }
+        })
+        .map(Chunk::from);
+    Box::new(future)
+}
+
+fn too_large() -> hyper::Error {
+    hyper::Error::from(io::Error::other(PayloadTooLarge))
+}
+
+pub fn is_payload_too_large(error: &hyper::Error) -> bool {
+    match *error {
+        hyper::Error::Io(ref error) => error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<PayloadTooLarge>()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+
+    #[test]
+    fn limits_each_key_separately() {
+        let limiter = MemoryRateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).is_err());
+        assert!(limiter.check_at("b", now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_the_period() {
+        let limiter = MemoryRateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        limiter.check_at("a", now).unwrap();
+        limiter.check_at("a", now).unwrap();
+
+        let retry_after = limiter.check_at("a", now).unwrap_err();
+        assert_eq!(30, retry_after.as_secs());
+
+        assert!(limiter.check_at("a", now + Duration::from_secs(29)).is_err());
+        assert!(limiter.check_at("a", now + Duration::from_secs(31)).is_ok());
+    }
+
+    fn post(body: &'static str, content_length: Option<u64>) -> Request {
+        let mut request = Request::new(Method::Post, "/api".parse().unwrap());
+        if let Some(length) = content_length {
+            request.headers_mut().set(ContentLength(length));
+        }
+        request.set_body(body);
+        request
+    }
+
+    #[test]
+    fn read_body_enforces_the_limit() {
+        let body = read_body(post("message=hello", None), 13).wait().unwrap();
+        assert_eq!(b"message=hello", &*body);
+
+        let error = read_body(post("message=hello", None), 12).wait().unwrap_err();
+        assert!(is_payload_too_large(&error));
+    }
+
+    #[test]
+    fn read_body_trusts_a_large_content_length() {
+        let error = read_body(post("", Some(1 << 20)), 12).wait().unwrap_err();
+        assert!(is_payload_too_large(&error));
+    }
+}
@@ -0,0 +1,258 @@
+use env_logger::Builder;
+use hex;
+use futures::{Async, Future, Poll};
+use hyper::{self, Method};
+use hyper::server::{Request, Response};
+use log::Record;
+use rand::{self, Rng};
+use serde_json::Value;
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::Instant;
+
+header! { (XRequestId, "X-Request-Id") => [String] }
+
+/// Longest `X-Request-Id` propagated from a client; longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format '{}', expected 'text' or 'json'", format)),
+        }
+    }
+}
+
+/// Installs the logger, filtered by `RUST_LOG` as before. Records logged while
+/// a request is being handled carry its id.
+pub fn init(format: LogFormat) {
+    let mut builder = Builder::from_default_env();
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let timestamp = buf.timestamp();
+            write!(buf, "{} {:<5} {}: ", timestamp, record.level(), record.target())?;
+            CURRENT.with(|current| match *current.borrow() {
+                Some(ref fields) => write!(buf, "[{}] ", fields.request_id),
+                None => Ok(()),
+            })?;
+            writeln!(buf, "{}", record.args())
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            writeln!(buf, "{}", json_record(&timestamp, record))
+        }),
+    };
+    builder.init();
+}
+
+fn json_record(timestamp: &str, record: &Record) -> Value {
+    let mut object = json!({
+        "timestamp": timestamp,
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    CURRENT.with(|current| {
+        if let Some(ref fields) = *current.borrow() {
+            if let (&mut Value::Object(ref mut map), Value::Object(span)) =
+                (&mut object, fields.to_json())
+            {
+                map.extend(span);
+            }
+        }
+    });
+    object
+}
+
+/// What is known about the request being handled on this thread.
+#[derive(Clone)]
+struct Fields {
+    request_id: String,
+    method: Method,
+    path: String,
+    status: Option<u16>,
+    latency_ms: Option<u64>,
+}
+
+impl Fields {
+    fn to_json(&self) -> Value {
+        let mut object = json!({
+            "request_id": self.request_id,
+            "method": self.method.to_string(),
+            "path": self.path,
+        });
+        if let Some(status) = self.status {
+            object["status"] = json!(status);
+        }
+        if let Some(latency_ms) = self.latency_ms {
+            object["latency_ms"] = json!(latency_ms);
+        }
+        object
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Fields>> = const { RefCell::new(None) };
+}
+
+/// The lifetime of one request, from the moment it is routed until its
+/// response is ready.
+pub struct Span {
+    fields: Fields,
+    start: Instant,
+}
+
+impl Span {
+    /// Starts a span for `request`, keeping its `X-Request-Id` when it is
+    /// usable and generating one otherwise.
+    pub fn new(request: &Request) -> Span {
+        let request_id = match request.headers().get::<XRequestId>() {
+            Some(id) if is_valid_request_id(id) => id.to_string(),
+            _ => generate_request_id(),
+        };
+        Span {
+            fields: Fields {
+                request_id,
+                method: request.method().clone(),
+                path: request.path().to_string(),
+                status: None,
+                latency_ms: None,
+            },
+            start: Instant::now(),
+        }
+    }
+
+    /// Runs `f` with this span as the current one, so that its records carry
+    /// the request id.
+    pub fn enter<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        enter(&self.fields, f)
+    }
+
+    /// Wraps the future of the response, entering the span whenever it is
+    /// polled and logging the outcome once it completes.
+    pub fn instrument<F>(self, future: F) -> Instrumented<F>
+    where
+        F: Future<Item = Response, Error = hyper::Error>,
+    {
+        Instrumented { future, span: self }
+    }
+
+    fn finish(&self, status: u16) {
+        let latency = self.start.elapsed();
+        let mut fields = self.fields.clone();
+        fields.status = Some(status);
+        fields.latency_ms = Some(latency.as_secs() * 1000 + u64::from(latency.subsec_millis()));
+        enter(&fields, || {
+            info!(
+                "{} {} {} {}ms",
+                fields.method,
+                fields.path,
+                status,
+                fields.latency_ms.unwrap()
+            )
+        });
+    }
+}
+
+fn enter<F, T>(fields: &Fields, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let previous = CURRENT.with(|current| current.replace(Some(fields.clone())));
+    let result = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+pub struct Instrumented<F> {
+    future: F,
+    span: Span,
+}
+
+impl<F> Future for Instrumented<F>
+where
+    F: Future<Item = Response, Error = hyper::Error>,
+{
+    type Item = Response;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Response, hyper::Error> {
+        let future = &mut self.future;
+        match self.span.enter(|| future.poll()) {
+            Ok(Async::Ready(mut response)) => {
+                self.span.finish(response.status().as_u16());
+                let request_id = self.span.fields.request_id.clone();
+                response.headers_mut().set(XRequestId(request_id));
+                Ok(Async::Ready(response))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(error) => {
+                self.span.enter(|| error!("Error handling request: {}", error));
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Client ids end up in logs and response headers, so only short printable
+/// ones are trusted.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+fn generate_request_id() -> String {
+    let bytes: [u8; 8] = rand::thread_rng().gen();
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn propagates_usable_request_ids() {
+        let mut request = Request::new(Method::Get, "/".parse().unwrap());
+        request.headers_mut().set(XRequestId("abc-123".to_string()));
+        assert_eq!("abc-123", Span::new(&request).fields.request_id);
+
+        request.headers_mut().set(XRequestId("not valid".to_string()));
+        let generated = Span::new(&request).fields.request_id;
+        assert_eq!(16, generated.len());
+        assert!(generated.bytes().all(|byte| byte.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn json_records_carry_the_span() {
+        let request = Request::new(Method::Post, "/api".parse().unwrap());
+        let span = Span::new(&request);
+        let args = format_args!("stored");
+        let record = Record::builder().args(args).target("microservice").build();
+
+        let object = span.enter(|| json_record("now", &record));
+        assert_eq!(json!("stored"), object["message"]);
+        assert_eq!(json!("POST"), object["method"]);
+        assert_eq!(json!("/api"), object["path"]);
+        assert_eq!(json!(span.fields.request_id), object["request_id"]);
+
+        let outside = json_record("now", &record);
+        assert!(outside.get("request_id").is_none());
+    }
+}
@@ -1,5 +1,10 @@
+#[macro_use]
 extern crate hyper;
 extern crate futures;
+extern crate tokio_core;
+
+#[macro_use]
+extern crate diesel;
 
 #[macro_use]
 extern crate log;
@@ -8,36 +13,73 @@ extern crate env_logger;
 #[macro_use]
 extern crate serde_json;
 extern crate url;
+extern crate clap;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate hex;
+extern crate rand;
+extern crate sha2;
 
+mod admin;
+mod auth;
+mod config;
+mod events;
+#[cfg(test)]
+mod harness;
+mod limits;
+mod logging;
+mod migrations;
+mod models;
+mod openapi;
+mod pagination;
+mod schema;
+mod search;
+mod store;
 
-use hyper::{Chunk, StatusCode};
-use hyper::Method::{Get, Post};
-use hyper::server::{Request, Response, Service};
-use hyper::header::{ContentLength, ContentType};
+use hyper::{Body, Chunk, StatusCode};
+use hyper::Method::{Delete, Get, Patch, Post};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType};
 
-use futures::Stream;
+use futures::{Sink, Stream};
 use futures::future::{Future, FutureResult};
 
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+
+use clap::{App, Arg};
+
 use std::collections::HashMap;
 use std::io;
-use std::error::Error;
+use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
+use auth::{AuthConfig, AuthError};
+use config::Config;
+use events::Broadcaster;
+use limits::{Limits, RateLimiter};
+use logging::Span;
+use models::{Message, MessageEdit, NewMessage, User};
+use pagination::{Cursor, Direction, Page, PageRequest, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use search::SearchResult;
+use store::{MessageStore, Store, StoreError};
 
-struct NewMessage {
-    username: String,
-    message: String,
-}
+header! { (LastEventId, "Last-Event-ID") => [String] }
 
-fn parse_form(form_chunk: Chunk) -> FutureResult<NewMessage, hyper::Error> {
+/// The author is always the authenticated `username`, never a form field.
+fn parse_form(form_chunk: Chunk, username: String) -> FutureResult<NewMessage, hyper::Error> {
     let mut form = url::form_urlencoded::parse(form_chunk.as_ref())
         .into_owned()
         .collect::<HashMap<String, String>>();
 
     if let Some(message) = form.remove("message") {
-        let username = form.remove("username").unwrap_or(String::from("anonymous"));
         futures::future::ok(NewMessage {
-            username: username,
-            message: message,
+            username,
+            message,
         })
     } else {
         futures::future::err(hyper::Error::from(io::Error::new(
@@ -47,8 +89,63 @@ fn parse_form(form_chunk: Chunk) -> FutureResult<NewMessage, hyper::Error> {
     }
 }
 
-fn write_to_db(entry: NewMessage) -> FutureResult<i64, hyper::Error> {
-    futures::future::ok(0)
+/// Whether `error` is the client's fault, as a form `parse_form` rejected.
+fn is_invalid_input(error: &hyper::Error) -> bool {
+    match *error {
+        hyper::Error::Io(ref error) => error.kind() == io::ErrorKind::InvalidInput,
+        _ => false,
+    }
+}
+
+fn write_to_db(
+    entry: NewMessage,
+    store: &dyn MessageStore,
+    events: &Broadcaster,
+) -> FutureResult<i64, hyper::Error> {
+    match store.insert(entry) {
+        Ok(message) => {
+            events.publish(&message);
+            futures::future::ok(message.timestamp)
+        }
+        Err(error) => {
+            error!("Error writing to database: {}", error);
+            futures::future::err(hyper::Error::from(io::Error::other("service error")))
+        }
+    }
+}
+
+fn edit_in_db(
+    id: i32,
+    entry: NewMessage,
+    store: &dyn MessageStore,
+) -> FutureResult<Option<Message>, hyper::Error> {
+    match store.edit(id, &entry.message, &entry.username) {
+        Ok(message) => futures::future::ok(message),
+        Err(error) => {
+            error!("Error editing message {}: {}", id, error);
+            futures::future::err(hyper::Error::from(io::Error::other("service error")))
+        }
+    }
+}
+
+fn query_db(time_range: TimeRange, store: &dyn MessageStore) -> Option<Vec<Message>> {
+    match store.query(&time_range) {
+        Ok(messages) => Some(messages),
+        Err(error) => {
+            error!("Error querying database: {}", error);
+            None
+        }
+    }
+}
+
+fn page_db(time_range: &TimeRange, request: &PageRequest, store: &dyn MessageStore) -> Option<Page> {
+    match store.page(time_range, request) {
+        Ok(page) => Some(page),
+        Err(error) => {
+            error!("Error querying database: {}", error);
+            None
+        }
+    }
 }
 
 fn make_post_response(
@@ -64,10 +161,58 @@ fn make_post_response(
             debug!("{:?}", response);
             futures::future::ok(response)
         }
-        Err(error) => make_error_response(error.description()),
+        Err(ref error) if limits::is_payload_too_large(error) => make_payload_too_large_response(),
+        Err(ref error) if is_invalid_input(error) => make_bad_request_response(&error.to_string()),
+        Err(error) => make_error_response(&error.to_string()),
+    }
+}
+
+fn make_edit_response(
+    result: Result<Option<Message>, hyper::Error>,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    match result {
+        Ok(Some(message)) => make_json_response(StatusCode::Ok, &message.to_json()),
+        Ok(None) => make_not_found_response(),
+        Err(ref error) if limits::is_payload_too_large(error) => make_payload_too_large_response(),
+        Err(ref error) if is_invalid_input(error) => make_bad_request_response(&error.to_string()),
+        Err(error) => make_error_response(&error.to_string()),
+    }
+}
+
+fn make_delete_response(result: Result<bool, StoreError>) -> FutureResult<hyper::Response, hyper::Error> {
+    match result {
+        Ok(true) => futures::future::ok(Response::new().with_status(StatusCode::NoContent)),
+        Ok(false) => make_not_found_response(),
+        Err(error) => {
+            error!("Error deleting message: {}", error);
+            make_error_response("service error")
+        }
     }
 }
 
+fn make_history_response(
+    history: Result<Vec<MessageEdit>, StoreError>,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    match history {
+        Ok(history) => {
+            let history = history.iter().map(MessageEdit::to_json).collect::<Vec<_>>();
+            make_json_response(StatusCode::Ok, &json!({ "history": history }))
+        }
+        Err(error) => {
+            error!("Error querying message history: {}", error);
+            make_error_response("service error")
+        }
+    }
+}
+
+fn make_not_found_response() -> FutureResult<hyper::Response, hyper::Error> {
+    make_json_response(StatusCode::NotFound, &json!({"error": "No such message"}))
+}
+
+fn make_bad_request_response(error_message: &str) -> FutureResult<hyper::Response, hyper::Error> {
+    make_json_response(StatusCode::BadRequest, &json!({"error": error_message}))
+}
+
 fn make_error_response(error_message: &str) -> FutureResult<hyper::Response, hyper::Error> {
     let payload = json!({"error": error_message}).to_string();
     let response = Response::new()
@@ -79,39 +224,237 @@ fn make_error_response(error_message: &str) -> FutureResult<hyper::Response, hyp
     futures::future::ok(response)
 }
 
-fn parse_query(query: &str) -> Result<TimeRange, String> {
-    let args = url::form_urlencoded::parse(&query.as_bytes())
-        .into_owned()
-        .collect::<HashMap<String, String>>();
+fn make_json_response(
+    status: StatusCode,
+    payload: &serde_json::Value,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    let payload = payload.to_string();
+    let response = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(payload.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(payload);
+    debug!("{:?}", response);
+    futures::future::ok(response)
+}
 
-    // TODO: refactor into a single function
-    let before = args.get("before").map(|value| value.parse::<i64>());
-    if let Some(ref result) = before {
-        if let Err(ref error) = *result {
-            return Err(format!("Error parsing 'before': {}", error));
+fn make_ready_response(ping: Result<(), StoreError>) -> FutureResult<hyper::Response, hyper::Error> {
+    match ping {
+        Ok(()) => make_json_response(StatusCode::Ok, &json!({"status": "ready"})),
+        Err(error) => {
+            warn!("Readiness check failed: {}", error);
+            make_json_response(
+                StatusCode::ServiceUnavailable,
+                &json!({"status": "unavailable", "error": error.to_string()}),
+            )
         }
     }
+}
+
+fn make_unauthorized_response(error_message: &str) -> FutureResult<hyper::Response, hyper::Error> {
+    let payload = json!({"error": error_message}).to_string();
+    let mut response = Response::new()
+        .with_status(StatusCode::Unauthorized)
+        .with_header(ContentLength(payload.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(payload);
+    response.headers_mut().set_raw("WWW-Authenticate", "Bearer");
+    debug!("{:?}", response);
+    futures::future::ok(response)
+}
+
+fn make_payload_too_large_response() -> FutureResult<hyper::Response, hyper::Error> {
+    make_json_response(
+        StatusCode::PayloadTooLarge,
+        &json!({"error": "Request body too large"}),
+    )
+}
+
+fn make_too_many_requests_response(retry_after: Duration) -> FutureResult<hyper::Response, hyper::Error> {
+    let payload = json!({"error": "Too many requests"}).to_string();
+    let mut response = Response::new()
+        .with_status(StatusCode::TooManyRequests)
+        .with_header(ContentLength(payload.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(payload);
+    // Retry-After takes whole seconds, so round up rather than invite a
+    // retry that is still too early.
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response.headers_mut().set_raw("Retry-After", seconds.to_string());
+    debug!("{:?}", response);
+    futures::future::ok(response)
+}
 
-    // TODO: refactor into a single function
-    let after = args.get("after").map(|value| value.parse::<i64>());
-    if let Some(ref result) = after {
-        if let Err(ref error) = *result {
-            return Err(format!("Error parsing 'after': {}", error));
+fn make_auth_error_response(error: AuthError) -> FutureResult<hyper::Response, hyper::Error> {
+    match error {
+        AuthError::MissingCredentials => make_unauthorized_response("Missing API key"),
+        AuthError::InvalidCredentials => make_unauthorized_response("Invalid API key"),
+        AuthError::Store(error) => {
+            error!("Error looking up API key: {}", error);
+            make_error_response("service error")
         }
     }
+}
+
+fn parse_query(query: &str) -> Result<(TimeRange, PageRequest), String> {
+    let args = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+
+    let before = parse_timestamp(&args, "before")?;
+    let after = parse_timestamp(&args, "after")?;
+    let page = parse_page(&args)?;
+
+    Ok((TimeRange { before, after }, page))
+}
+
+#[derive(Clone, Copy)]
+enum MessageRoute {
+    Message(i32),
+    History(i32),
+}
+
+/// Matches `/api/messages/:id` and `/api/messages/:id/history`.
+fn parse_message_route(path: &str) -> Option<MessageRoute> {
+    let mut segments = path.strip_prefix("/api/messages/")?.split('/');
+    let id = segments.next()?.parse::<i32>().ok()?;
+    match (segments.next(), segments.next()) {
+        (None, _) => Some(MessageRoute::Message(id)),
+        (Some("history"), None) => Some(MessageRoute::History(id)),
+        _ => None,
+    }
+}
 
-    Ok(TimeRange {
-        before: before.map(|b| b.unwrap()),
-        after: after.map(|a| a.unwrap()),
+fn parse_search_query(query: &str) -> Result<(String, TimeRange), String> {
+    let args = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+
+    let terms = match args.get("q") {
+        Some(terms) if !terms.trim().is_empty() => terms.clone(),
+        _ => return Err(String::from("Missing field 'q'")),
+    };
+    let before = parse_timestamp(&args, "before")?;
+    let after = parse_timestamp(&args, "after")?;
+
+    Ok((terms, TimeRange { before, after }))
+}
+
+fn parse_page(args: &HashMap<String, String>) -> Result<PageRequest, String> {
+    let limit = match args.get("limit").map(|value| value.parse::<usize>()) {
+        Some(Ok(limit)) if (1..=MAX_PAGE_SIZE).contains(&limit) => limit,
+        Some(Ok(_)) => return Err(format!("'limit' must be between 1 and {}", MAX_PAGE_SIZE)),
+        Some(Err(error)) => return Err(format!("Error parsing 'limit': {}", error)),
+        None => DEFAULT_PAGE_SIZE,
+    };
+    let cursor = match args.get("cursor") {
+        Some(cursor) => Some(Cursor::decode(cursor)?),
+        None => None,
+    };
+    Ok(PageRequest { cursor, limit })
+}
+
+/// Reads `Last-Event-ID`: a cursor from `format_event`, or the bare
+/// timestamp that older clients may still send, which resumes after every
+/// message of that second.
+fn parse_last_event_id(id: &str) -> Result<Cursor, String> {
+    if let Ok(timestamp) = id.parse::<i64>() {
+        return Ok(Cursor {
+            timestamp,
+            id: i32::MAX,
+            direction: Direction::Forward,
+        });
+    }
+    let cursor = Cursor::decode(id)?;
+    Ok(Cursor {
+        direction: Direction::Forward,
+        ..cursor
     })
 }
 
+fn parse_timestamp(args: &HashMap<String, String>, name: &str) -> Result<Option<i64>, String> {
+    match args.get(name).map(|value| value.parse::<i64>()) {
+        Some(Ok(timestamp)) => Ok(Some(timestamp)),
+        Some(Err(error)) => Err(format!("Error parsing '{}': {}", name, error)),
+        None => Ok(None),
+    }
+}
+
+fn render_page(page: &Page, time_range: &TimeRange, limit: usize) -> String {
+    let items = page
+        .messages
+        .iter()
+        .map(|message| {
+            let edited = match message.edited_at {
+                Some(edited_at) => format!(", edited {}", edited_at),
+                None => String::new(),
+            };
+            format!(
+                "<li>{} ({}{}): {}</li>",
+                escape_html(&message.username),
+                message.timestamp,
+                edited,
+                escape_html(&message.message)
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<!DOCTYPE html><html><head><title>microservice</title>\
+         <style>body {{ font-family: monospace }}</style></head>\
+         <body><ul>{}</ul>{}</body></html>",
+        items,
+        render_page_links(page, time_range, limit)
+    )
+}
+
+fn render_page_links(page: &Page, time_range: &TimeRange, limit: usize) -> String {
+    let links = [("prev", page.prev), ("next", page.next)]
+        .iter()
+        .filter_map(|&(label, cursor)| {
+            cursor.map(|cursor| {
+                let href = page_link(time_range, limit, &cursor);
+                format!("<a rel=\"{0}\" href=\"{1}\">{0}</a>", label, escape_html(&href))
+            })
+        })
+        .collect::<Vec<_>>();
+    if links.is_empty() {
+        String::new()
+    } else {
+        format!("<nav>{}</nav>", links.join(" "))
+    }
+}
+
+/// The URL of the page at `cursor`, keeping the filters of the current one.
+fn page_link(time_range: &TimeRange, limit: usize, cursor: &Cursor) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(before) = time_range.before {
+        query.append_pair("before", &before.to_string());
+    }
+    if let Some(after) = time_range.after {
+        query.append_pair("after", &after.to_string());
+    }
+    if limit != DEFAULT_PAGE_SIZE {
+        query.append_pair("limit", &limit.to_string());
+    }
+    query.append_pair("cursor", &cursor.encode());
+    format!("/?{}", query.finish())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 fn make_get_response(
-    messages: Option<Vec<NewMessage>>,
+    page: Option<Page>,
+    time_range: &TimeRange,
+    limit: usize,
 ) -> FutureResult<hyper::Response, hyper::Error> {
-    let response = match messages {
-        Some(messages) => {
-            let body = render_page(messages);
+    let response = match page {
+        Some(page) => {
+            let body = render_page(&page, time_range, limit);
             Response::new()
                 .with_header(ContentLength(body.len() as u64))
                 .with_body(body)
@@ -122,41 +465,288 @@ fn make_get_response(
     futures::future::ok(response)
 }
 
-struct Microservice;
+fn make_search_response(
+    results: Result<Vec<SearchResult>, StoreError>,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    match results {
+        Ok(results) => {
+            let results = results
+                .iter()
+                .map(|result| {
+                    let mut json = result.message.to_json();
+                    json["rank"] = json!(result.rank);
+                    json["snippet"] = json!(result.snippet);
+                    json
+                })
+                .collect::<Vec<_>>();
+            let payload = json!({ "results": results }).to_string();
+            let response = Response::new()
+                .with_header(ContentLength(payload.len() as u64))
+                .with_header(ContentType::json())
+                .with_body(payload);
+            debug!("{:?}", response);
+            futures::future::ok(response)
+        }
+        Err(error) => {
+            error!("Error searching database: {}", error);
+            make_error_response("service error")
+        }
+    }
+}
+
+fn make_stream_response(
+    last_event_id: Option<Cursor>,
+    store: &dyn MessageStore,
+    events: &Broadcaster,
+    handle: &Handle,
+) -> FutureResult<hyper::Response, hyper::Error> {
+    // Subscribe before looking up the backlog so nothing written in between
+    // is lost; live messages already covered by the backlog are skipped.
+    let live = events.subscribe();
+    let backlog = match last_event_id {
+        Some(cursor) => {
+            // The range is by the second; the cursor sorts out the messages
+            // of its own second. There is no second before the earliest.
+            let time_range = TimeRange {
+                before: None,
+                after: cursor.timestamp.checked_sub(1),
+            };
+            match query_db(time_range, store) {
+                Some(messages) => messages
+                    .into_iter()
+                    .filter(|message| cursor.admits(message))
+                    .collect(),
+                None => return make_error_response("service error"),
+            }
+        }
+        None => Vec::new(),
+    };
+    let last_backlog_id = backlog.last().map_or(0, |message| message.id);
+
+    let messages = futures::stream::iter_ok(backlog)
+        .chain(live.filter(move |message| message.id > last_backlog_id))
+        .map(|message| Ok(Chunk::from(events::format_event(&message))));
+
+    let (sender, body) = Body::pair();
+    handle.spawn(
+        sender
+            .sink_map_err(|_| ())
+            .send_all(messages)
+            .map(|_| ()),
+    );
+
+    let response = Response::new()
+        .with_header(ContentType(hyper::mime::TEXT_EVENT_STREAM))
+        .with_header(CacheControl(vec![CacheDirective::NoCache]))
+        .with_body(body);
+    debug!("{:?}", response);
+    futures::future::ok(response)
+}
+
+struct Microservice {
+    store: Arc<dyn Store>,
+    events: Arc<Broadcaster>,
+    auth: Arc<AuthConfig>,
+    limits: Arc<Limits>,
+    handle: Handle,
+    remote_addr: SocketAddr,
+}
 
 struct TimeRange {
     before: Option<i64>,
     after: Option<i64>,
 }
 
+impl TimeRange {
+    fn contains(&self, timestamp: i64) -> bool {
+        self.before.is_none_or(|before| timestamp < before)
+            && self.after.is_none_or(|after| timestamp > after)
+    }
+}
+
+fn check_rate_limit(
+    limiter: &Option<Box<dyn RateLimiter>>,
+    key: &str,
+) -> Result<(), FutureResult<hyper::Response, hyper::Error>> {
+    match limiter.as_ref().map(|limiter| limiter.check(key)) {
+        Some(Err(retry_after)) => {
+            debug!("Rate limited {}", key);
+            Err(make_too_many_requests_response(retry_after))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Microservice {
+    fn check_ip_limit(&self) -> Result<(), FutureResult<hyper::Response, hyper::Error>> {
+        check_rate_limit(&self.limits.per_ip, &self.remote_addr.ip().to_string())
+    }
+
+    fn check_user_limit(&self, user: &User) -> Result<(), FutureResult<hyper::Response, hyper::Error>> {
+        check_rate_limit(&self.limits.per_user, &user.username)
+    }
+
+    /// Authenticates a user who may change or audit message `id`: its author
+    /// or an admin. Anonymous users never can.
+    fn authorize_editor(
+        &self,
+        request: &Request,
+        id: i32,
+    ) -> Result<(User, Message), FutureResult<hyper::Response, hyper::Error>> {
+        let config = AuthConfig {
+            allow_anonymous: false,
+        };
+        let user = match auth::authenticate(request.headers(), &*self.store, &config) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(make_auth_error_response(AuthError::MissingCredentials)),
+            Err(error) => return Err(make_auth_error_response(error)),
+        };
+        self.check_user_limit(&user)?;
+        let message = match self.store.find(id) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(make_not_found_response()),
+            Err(error) => {
+                error!("Error looking up message {}: {}", id, error);
+                return Err(make_error_response("service error"));
+            }
+        };
+        // Anonymous posts have no author to speak of.
+        let is_author = user.username == message.username && message.username != auth::ANONYMOUS;
+        if user.is_admin || is_author {
+            Ok((user, message))
+        } else {
+            Err(make_json_response(
+                StatusCode::Forbidden,
+                &json!({"error": "Only the author or an admin can do this"}),
+            ))
+        }
+    }
+}
+
 impl Service for Microservice {
     type Request = Request;
     type Response = Response;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, request: Request) -> Self::Future {
-        match (request.method(), request.path()) {
-            (&Post, "/api") => {
-                let future = request
-                    .body()
-                    .concat2()
-                    .and_then(parse_form)
-                    .and_then(write_to_db)
+        let span = Span::new(&request);
+        let future = span.enter(|| self.route(request));
+        Box::new(span.instrument(future))
+    }
+}
+
+impl Microservice {
+    fn route(&self, request: Request) -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+        let message_route = parse_message_route(request.path());
+        match (request.method(), request.path(), message_route) {
+            (&Post, "/api", _) => {
+                if let Err(response) = self.check_ip_limit() {
+                    return Box::new(response);
+                }
+                let username = match auth::authenticate(request.headers(), &*self.store, &self.auth) {
+                    Ok(Some(user)) => {
+                        if let Err(response) = self.check_user_limit(&user) {
+                            return Box::new(response);
+                        }
+                        user.username
+                    }
+                    Ok(None) => String::from(auth::ANONYMOUS),
+                    Err(error) => return Box::new(make_auth_error_response(error)),
+                };
+                let store = Arc::clone(&self.store);
+                let events = Arc::clone(&self.events);
+                let future = limits::read_body(request, self.limits.max_body_size)
+                    .and_then(move |form_chunk| parse_form(form_chunk, username))
+                    .and_then(move |entry| write_to_db(entry, &*store, &events))
                     .then(make_post_response);
                 Box::new(future)
             }
-            (&Get, "/") => {
-                let time_range = match request.query() {
-                    Some(query) => parse_query(query),
-                    None => Ok(TimeRange {
-                        before: None,
-                        after: None,
-                    }),
+            (&Get, "/api/stream", _) => {
+                let last_event_id = request
+                    .headers()
+                    .get::<LastEventId>()
+                    .map(|id| parse_last_event_id(id));
+                let response = match last_event_id {
+                    Some(Err(error)) => {
+                        make_bad_request_response(&format!("Error parsing 'Last-Event-ID': {}", error))
+                    }
+                    Some(Ok(cursor)) => {
+                        make_stream_response(Some(cursor), &*self.store, &self.events, &self.handle)
+                    }
+                    None => make_stream_response(None, &*self.store, &self.events, &self.handle),
+                };
+                Box::new(response)
+            }
+            (&Get, "/api/search", _) => {
+                let response = match parse_search_query(request.query().unwrap_or("")) {
+                    Ok((terms, time_range)) => {
+                        make_search_response(self.store.search(&terms, &time_range))
+                    }
+                    Err(error) => make_bad_request_response(&error),
+                };
+                Box::new(response)
+            }
+            (&Get, "/", _) => {
+                let response = match parse_query(request.query().unwrap_or("")) {
+                    Ok((time_range, page)) => make_get_response(
+                        page_db(&time_range, &page, &*self.store),
+                        &time_range,
+                        page.limit,
+                    ),
+                    Err(error) => make_bad_request_response(&error),
+                };
+                Box::new(response)
+            }
+            (&Get, "/api/messages", _) => {
+                let response = match parse_query(request.query().unwrap_or("")) {
+                    Ok((time_range, page)) => match page_db(&time_range, &page, &*self.store) {
+                        Some(page) => make_json_response(StatusCode::Ok, &page.to_json()),
+                        None => make_error_response("service error"),
+                    },
+                    Err(error) => make_bad_request_response(&error),
+                };
+                Box::new(response)
+            }
+            (&Get, "/openapi.json", _) => {
+                Box::new(make_json_response(StatusCode::Ok, &openapi::document()))
+            }
+            (&Get, "/healthz", _) => {
+                Box::new(make_json_response(StatusCode::Ok, &json!({"status": "ok"})))
+            }
+            (&Get, "/readyz", _) => Box::new(make_ready_response(self.store.ping())),
+            (&Patch, _, Some(MessageRoute::Message(id))) => {
+                if let Err(response) = self.check_ip_limit() {
+                    return Box::new(response);
+                }
+                let user = match self.authorize_editor(&request, id) {
+                    Ok((_, ref message)) if message.deleted_at.is_some() => {
+                        return Box::new(make_not_found_response())
+                    }
+                    Ok((user, _)) => user,
+                    Err(response) => return Box::new(response),
+                };
+                let store = Arc::clone(&self.store);
+                let future = limits::read_body(request, self.limits.max_body_size)
+                    .and_then(move |form_chunk| parse_form(form_chunk, user.username))
+                    .and_then(move |entry| edit_in_db(id, entry, &*store))
+                    .then(make_edit_response);
+                Box::new(future)
+            }
+            (&Delete, _, Some(MessageRoute::Message(id))) => {
+                if let Err(response) = self.check_ip_limit() {
+                    return Box::new(response);
+                }
+                let response = match self.authorize_editor(&request, id) {
+                    Ok((user, _)) => make_delete_response(self.store.delete(id, &user.username)),
+                    Err(response) => response,
                 };
-                let response = match time_range {
-                    Ok(time_range) => make_get_response(query_db(time_range)),
-                    Err(error) => make_error_response(&error),
+                Box::new(response)
+            }
+            (&Get, _, Some(MessageRoute::History(id))) => {
+                let response = match self.authorize_editor(&request, id) {
+                    Ok(_) => make_history_response(self.store.history(id)),
+                    Err(response) => response,
                 };
                 Box::new(response)
             }
@@ -168,12 +758,450 @@ impl Service for Microservice {
 }
 
 fn main() {
-    env_logger::init();
-    let address = "127.0.0.1:8080".parse().unwrap();
-    let server = hyper::server::Http::new()
-        .bind(&address, || Ok(Microservice {}))
-        .unwrap();
-    info!("Running microservice at {}", address);
-    server.run().unwrap();
+    let matches = App::new("microservice")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .global(true)
+                .help("Reads settings from a TOML file, which the environment overrides"),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Prints the effective config, with secrets redacted, and exits"),
+        )
+        .subcommand(admin::keys_subcommand())
+        .subcommand(admin::migrate_subcommand())
+        .get_matches();
+
+    let config = match Config::load(matches.value_of("config")) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Error loading config: {}", error);
+            process::exit(1);
+        }
+    };
+    if matches.is_present("print-config") {
+        print!("{}", config.redacted());
+        return;
+    }
+    logging::init(config.log_format);
+
+    match matches.subcommand() {
+        ("keys", Some(matches)) => {
+            if let Err(error) = admin::run_keys(matches, &config) {
+                eprintln!("Error managing keys: {}", error);
+                process::exit(1);
+            }
+        }
+        ("migrate", Some(matches)) => {
+            if let Err(error) = admin::run_migrate(matches, &config) {
+                eprintln!("Error migrating database: {}", error);
+                process::exit(1);
+            }
+        }
+        _ => serve(&config),
+    }
+}
+
+fn serve(config: &Config) {
+    let store: Arc<dyn Store> = match store::open(config) {
+        Ok(store) => Arc::from(store),
+        Err(error) => {
+            eprintln!("Error opening the message store: {}", error);
+            process::exit(1);
+        }
+    };
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = match TcpListener::bind(&config.address, &handle) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Error listening on {}: {}", config.address, error);
+            process::exit(1);
+        }
+    };
+    info!("Running microservice at {}", config.address);
+    core.run(accept(listener, store, config, &handle)).unwrap();
 }
 
+/// Serves every connection made to `listener` with a `Microservice` backed by
+/// `store`, until accepting fails.
+fn accept(
+    listener: TcpListener,
+    store: Arc<dyn Store>,
+    config: &Config,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = io::Error>> {
+    let events = Arc::new(Broadcaster::new());
+    let auth = Arc::new(AuthConfig {
+        allow_anonymous: config.allow_anonymous,
+    });
+    let limits = Arc::new(Limits::new(&config.limits));
+    let http = Http::<Chunk>::new();
+    let handle = handle.clone();
+
+    // Connections are accepted by hand, rather than with `serve_addr_handle`,
+    // so that each service knows its peer address for per-IP rate limiting.
+    // They are spawned onto the core so that streaming responses can keep
+    // running alongside regular requests.
+    Box::new(listener.incoming().for_each(move |(socket, remote_addr)| {
+        let service = Microservice {
+            store: Arc::clone(&store),
+            events: Arc::clone(&events),
+            auth: Arc::clone(&auth),
+            limits: Arc::clone(&limits),
+            handle: handle.clone(),
+            remote_addr,
+        };
+        handle.spawn(
+            http.serve_connection(socket, service)
+                .map(|_| ())
+                .map_err(|error| error!("Connection error: {}", error)),
+        );
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::LimitsConfig;
+    use harness::TestServer;
+    use store::{MemoryStore, UserStore};
+
+    fn messages(server: &TestServer, query: &str) -> Vec<String> {
+        let response = server.get(&format!("/api/messages{}", query)).send();
+        assert_eq!(200, response.status, "{}", response.text());
+        response.json()["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["message"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn message_id(server: &TestServer, text: &str) -> i64 {
+        let response = server.get("/api/messages").send();
+        response.json()["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|message| message["message"] == json!(text))
+            .map(|message| message["id"].as_i64().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn posts_and_lists_messages() {
+        let server = TestServer::start();
+        let timestamp = server.post_message("hello <world>");
+
+        let page = server.get("/").send();
+        assert_eq!(200, page.status);
+        assert!(page.text().contains(&format!("<li>anonymous ({}): hello &lt;world&gt;</li>", timestamp)));
+
+        let listed = server.get("/api/messages").send().json();
+        assert_eq!(json!("anonymous"), listed["messages"][0]["username"]);
+        assert_eq!(json!(timestamp), listed["messages"][0]["timestamp"]);
+        assert_eq!(json!(null), listed["next_cursor"]);
+    }
+
+    #[test]
+    fn parse_form_errors() {
+        let server = TestServer::start();
+
+        let response = server.post("/api").form(&[("text", "hello")]).send();
+        assert_eq!(400, response.status);
+        assert_eq!("Missing field 'message", response.error());
+
+        let response = server.post("/api").send();
+        assert_eq!(400, response.status);
+        assert_eq!("Missing field 'message", response.error());
+
+        // Bodies are always read as forms.
+        let response = server.post("/api").json(&json!({"message": "hello"})).send();
+        assert_eq!(400, response.status);
+        assert_eq!("Missing field 'message", response.error());
+
+        assert!(messages(&server, "").is_empty());
+    }
+
+    #[test]
+    fn parse_query_errors() {
+        let server = TestServer::start();
+        let cases = [
+            ("?before=yesterday", "Error parsing 'before': invalid digit found in string"),
+            ("?after=", "Error parsing 'after': cannot parse integer from empty string"),
+            ("?limit=0", "'limit' must be between 1 and 200"),
+            ("?limit=ten", "Error parsing 'limit': invalid digit found in string"),
+            ("?cursor=nope", "Invalid cursor"),
+        ];
+        for &(query, error) in &cases {
+            for path in &["/", "/api/messages"] {
+                let response = server.get(&format!("{}{}", path, query)).send();
+                assert_eq!(400, response.status, "{}{}", path, query);
+                assert_eq!(error, response.error(), "{}{}", path, query);
+            }
+        }
+    }
+
+    #[test]
+    fn filters_by_time_range() {
+        let server = TestServer::start();
+        let timestamp = server.post_message("first");
+        server.post_message("second");
+
+        let range = |before: Option<i64>, after: Option<i64>| {
+            let mut query = String::from("?");
+            if let Some(before) = before {
+                query.push_str(&format!("before={}&", before));
+            }
+            if let Some(after) = after {
+                query.push_str(&format!("after={}", after));
+            }
+            messages(&server, &query)
+        };
+        // Both messages usually share a second; both bounds are exclusive.
+        assert!(range(Some(timestamp), None).is_empty());
+        assert!(range(None, Some(timestamp + 60)).is_empty());
+        assert_eq!(vec!["first", "second"], range(None, Some(timestamp - 1)));
+        assert_eq!(vec!["first", "second"], range(Some(timestamp + 60), Some(timestamp - 1)));
+        assert!(range(Some(timestamp - 1), Some(timestamp - 1)).is_empty());
+
+        let page = server.get(&format!("/?before={}", timestamp)).send();
+        assert!(!page.text().contains("<li>"));
+    }
+
+    #[test]
+    fn pages_through_messages() {
+        let server = TestServer::start();
+        for text in &["a", "b", "c"] {
+            server.post_message(text);
+        }
+
+        let first = server.get("/api/messages?limit=2").send().json();
+        let cursor = first["next_cursor"].as_str().unwrap().to_string();
+        assert_eq!(vec!["c"], messages(&server, &format!("?limit=2&cursor={}", cursor)));
+
+        let page = server.get("/?limit=2").send().text();
+        assert!(page.contains(&format!("href=\"/?limit=2&amp;cursor={}\"", cursor)));
+        assert!(!page.contains("rel=\"prev\""));
+    }
+
+    #[test]
+    fn requires_credentials_unless_anonymous_posts_are_allowed() {
+        let server = TestServer::with_config(Config {
+            allow_anonymous: false,
+            ..Config::default()
+        });
+
+        let response = server.post("/api").form(&[("message", "hi")]).send();
+        assert_eq!(401, response.status);
+        assert_eq!(Some("Bearer"), response.header("WWW-Authenticate"));
+        assert_eq!("Missing API key", response.error());
+
+        let response = server.post("/api").bearer("bogus").form(&[("message", "hi")]).send();
+        assert_eq!(401, response.status);
+        assert_eq!("Invalid API key", response.error());
+
+        let key = server.create_key("alice", false);
+        let response = server
+            .post("/api")
+            .header("X-Api-Key", &key)
+            .form(&[("message", "hi")])
+            .send();
+        assert_eq!(200, response.status);
+        let listed = server.get("/api/messages").send().json();
+        assert_eq!(json!("alice"), listed["messages"][0]["username"]);
+    }
+
+    #[test]
+    fn edits_deletes_and_audits_messages() {
+        let server = TestServer::start();
+        let alice = server.create_key("alice", false);
+        let bob = server.create_key("bob", false);
+        let admin = server.create_key("root", true);
+        server.post("/api").bearer(&alice).form(&[("message", "draft")]).send();
+        let path = format!("/api/messages/{}", message_id(&server, "draft"));
+
+        let response = server.patch(&path).form(&[("message", "final")]).send();
+        assert_eq!(401, response.status);
+        let response = server.patch(&path).bearer(&bob).form(&[("message", "mine")]).send();
+        assert_eq!(403, response.status);
+        let response = server.patch(&path).bearer(&alice).send();
+        assert_eq!(400, response.status);
+        assert_eq!("Missing field 'message", response.error());
+
+        let response = server.patch(&path).bearer(&alice).form(&[("message", "final")]).send();
+        assert_eq!(200, response.status);
+        assert_eq!(json!("final"), response.json()["message"]);
+        assert!(response.json()["edited_at"].is_i64());
+        assert!(server.get("/").send().text().contains(", edited "));
+
+        assert_eq!(403, server.delete(&path).bearer(&bob).send().status);
+        assert_eq!(204, server.delete(&path).bearer(&admin).send().status);
+        assert_eq!(404, server.delete(&path).bearer(&admin).send().status);
+        let response = server.patch(&path).bearer(&alice).form(&[("message", "again")]).send();
+        assert_eq!(404, response.status);
+        assert!(messages(&server, "").is_empty());
+
+        let history = server.get(&format!("{}/history", path)).bearer(&alice).send();
+        assert_eq!(200, history.status);
+        let history = history.json();
+        assert_eq!(json!("edit"), history["history"][0]["action"]);
+        assert_eq!(json!("draft"), history["history"][0]["message"]);
+        assert_eq!(json!("delete"), history["history"][1]["action"]);
+        assert_eq!(json!("root"), history["history"][1]["edited_by"]);
+        assert_eq!(403, server.get(&format!("{}/history", path)).bearer(&bob).send().status);
+
+        assert_eq!(404, server.delete("/api/messages/99").bearer(&admin).send().status);
+        assert_eq!(404, server.get("/api/messages/99/history").bearer(&admin).send().status);
+    }
+
+    #[test]
+    fn reserves_the_anonymous_username() {
+        let store = MemoryStore::new();
+        let error = store.create_key(auth::ANONYMOUS, &auth::hash_key("key")).unwrap_err();
+        assert_eq!("The username 'anonymous' is reserved", error.to_string());
+    }
+
+    #[test]
+    fn searches_messages() {
+        let server = TestServer::start();
+        server.post_message("hello world");
+        server.post_message("goodbye");
+
+        let response = server.get("/api/search?q=hello").send();
+        assert_eq!(200, response.status);
+        let results = response.json()["results"].as_array().unwrap().clone();
+        assert_eq!(1, results.len());
+        assert_eq!(json!("hello world"), results[0]["message"]);
+        assert_eq!(json!("<mark>hello</mark> world"), results[0]["snippet"]);
+
+        let response = server.get("/api/search").send();
+        assert_eq!(400, response.status);
+        assert_eq!("Missing field 'q'", response.error());
+        let response = server.get("/api/search?q=hello&after=soon").send();
+        assert_eq!("Error parsing 'after': invalid digit found in string", response.error());
+    }
+
+    #[test]
+    fn streams_backlog_then_live_messages() {
+        let server = TestServer::start();
+        let timestamp = server.post_message("before");
+
+        let text = server
+            .get("/api/stream")
+            .header("Last-Event-ID", &(timestamp - 1).to_string())
+            .read_stream("event: message", 2, || {
+                server.post_message("live");
+            });
+        assert!(text.contains("text/event-stream"));
+        let before = text.find("\"message\":\"before\"").unwrap();
+        let live = text.find("\"message\":\"live\"").unwrap();
+        assert!(before < live);
+
+        let response = server.get("/api/stream").header("Last-Event-ID", "later").send();
+        assert_eq!(400, response.status);
+        assert!(response.error().starts_with("Error parsing 'Last-Event-ID'"));
+    }
+
+    #[test]
+    fn resumes_between_messages_of_the_same_second() {
+        // Try again on a fresh server should the clock tick in between.
+        let (server, timestamp) = loop {
+            let server = TestServer::start();
+            let timestamp = server.post_message("first");
+            if server.post_message("second") == timestamp {
+                break (server, timestamp);
+            }
+        };
+
+        let text = server
+            .get("/api/stream")
+            .header("Last-Event-ID", &(timestamp - 1).to_string())
+            .read_stream("event: message", 2, || ());
+        let start = text.find("\"message\":\"first\"").unwrap();
+        let id = text[..start].rsplit("id: ").next().unwrap().lines().next().unwrap();
+
+        let text = server
+            .get("/api/stream")
+            .header("Last-Event-ID", id)
+            .read_stream("event: message", 1, || ());
+        assert!(!text.contains("\"message\":\"first\""));
+        assert!(text.contains("\"message\":\"second\""));
+
+        // A bare timestamp skips the whole second, as it always did.
+        let text = server
+            .get("/api/stream")
+            .header("Last-Event-ID", &timestamp.to_string())
+            .read_stream("event: message", 1, || {
+                server.post_message("later");
+            });
+        assert!(!text.contains("\"message\":\"second\""), "{}", text);
+    }
+
+    #[test]
+    fn resumes_from_the_earliest_timestamp() {
+        let server = TestServer::start();
+        server.post_message("old");
+
+        let text = server
+            .get("/api/stream")
+            .header("Last-Event-ID", &i64::MIN.to_string())
+            .read_stream("event: message", 1, || ());
+        assert!(text.contains("\"message\":\"old\""), "{}", text);
+    }
+
+    #[test]
+    fn serves_probes_and_documentation() {
+        let server = TestServer::start();
+        assert_eq!(json!({"status": "ok"}), server.get("/healthz").send().json());
+        assert_eq!(json!({"status": "ready"}), server.get("/readyz").send().json());
+
+        let document = server.get("/openapi.json").send();
+        assert_eq!(200, document.status);
+        assert_eq!(json!("3.0.0"), document.json()["openapi"]);
+
+        assert_eq!(404, server.get("/nowhere").send().status);
+        assert_eq!(404, server.request("PUT", "/api").send().status);
+        assert_eq!(404, server.get("/api/messages/1/edits").send().status);
+    }
+
+    #[test]
+    fn tags_responses_with_request_ids() {
+        let server = TestServer::start();
+        let response = server.get("/healthz").header("X-Request-Id", "abc-123").send();
+        assert_eq!(Some("abc-123"), response.header("X-Request-Id"));
+
+        let generated = server.get("/healthz").send();
+        assert_eq!(16, generated.header("X-Request-Id").unwrap().len());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let server = TestServer::with_config(Config {
+            limits: LimitsConfig {
+                max_body_size: 16,
+                rate_limit_per_ip: 2,
+                rate_limit_per_user: 0,
+            },
+            ..Config::default()
+        });
+
+        let response = server.post("/api").form(&[("message", "far too long to fit")]).send();
+        assert_eq!(413, response.status);
+        assert_eq!("Request body too large", response.error());
+
+        server.post_message("short");
+        let response = server.post("/api").form(&[("message", "again")]).send();
+        assert_eq!(429, response.status);
+        assert!(response.header("Retry-After").unwrap().parse::<u64>().unwrap() > 0);
+
+        // Reads are not limited.
+        assert_eq!(200, server.get("/").send().status);
+    }
+}
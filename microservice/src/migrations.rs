@@ -0,0 +1,138 @@
+use diesel;
+use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+
+use schema::schema_migrations;
+use store::StoreError;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, "/up.sql")),
+            down: include_str!(concat!("../migrations/", $name, "/down.sql")),
+        }
+    };
+}
+
+/// Every schema change, oldest first. The first migrations only create what
+/// is missing, so databases set up by hand from the old `schemas/` files can
+/// adopt them.
+pub static MIGRATIONS: &[Migration] = &[
+    migration!(1, "001_create_messages"),
+    migration!(2, "002_create_users"),
+    migration!(3, "003_add_search_index"),
+    migration!(4, "004_add_message_edits"),
+    migration!(5, "005_add_message_cursor_index"),
+];
+
+/// Versions recorded in the database, oldest first.
+pub fn applied_versions(connection: &PgConnection) -> Result<Vec<i32>, StoreError> {
+    connection.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+           version INTEGER PRIMARY KEY,
+           applied_at BIGINT NOT NULL DEFAULT EXTRACT('epoch' FROM CURRENT_TIMESTAMP)
+         )",
+    )?;
+    let versions = schema_migrations::table
+        .select(schema_migrations::version)
+        .order(schema_migrations::version.asc())
+        .load(connection)?;
+    Ok(versions)
+}
+
+pub fn pending(connection: &PgConnection) -> Result<Vec<&'static Migration>, StoreError> {
+    let applied = applied_versions(connection)?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+/// Applies every pending migration, each one in its own transaction.
+pub fn run_pending(connection: &PgConnection) -> Result<Vec<&'static Migration>, StoreError> {
+    let migrations = pending(connection)?;
+    for migration in &migrations {
+        connection.transaction::<_, diesel::result::Error, _>(|| {
+            connection.batch_execute(migration.up)?;
+            diesel::insert_into(schema_migrations::table)
+                .values(schema_migrations::version.eq(migration.version))
+                .execute(connection)?;
+            Ok(())
+        })?;
+        info!("Applied migration {}", migration.name);
+    }
+    Ok(migrations)
+}
+
+/// Reverts the most recently applied migration, if any.
+pub fn revert_latest(connection: &PgConnection) -> Result<Option<&'static Migration>, StoreError> {
+    check_known(connection)?;
+    let latest = match applied_versions(connection)?.last() {
+        Some(&version) => find(version).unwrap(),
+        None => return Ok(None),
+    };
+    connection.transaction::<_, diesel::result::Error, _>(|| {
+        connection.batch_execute(latest.down)?;
+        diesel::delete(schema_migrations::table.find(latest.version)).execute(connection)?;
+        Ok(())
+    })?;
+    info!("Reverted migration {}", latest.name);
+    Ok(Some(latest))
+}
+
+/// Fails unless the database is at exactly the latest known version.
+pub fn check(connection: &PgConnection) -> Result<(), StoreError> {
+    check_known(connection)?;
+    let pending = pending(connection)?;
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(StoreError::from(format!(
+            "The database has {} pending migration(s), run `microservice migrate`",
+            pending.len()
+        )))
+    }
+}
+
+/// Fails if the database was migrated by a newer or different build.
+fn check_known(connection: &PgConnection) -> Result<(), StoreError> {
+    match applied_versions(connection)?
+        .into_iter()
+        .find(|&version| find(version).is_none())
+    {
+        Some(version) => Err(StoreError::from(format!(
+            "Unknown database schema version {}",
+            version
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn find(version: i32) -> Option<&'static Migration> {
+    MIGRATIONS.iter().find(|migration| migration.version == version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_sequential() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(index as i32 + 1, migration.version);
+            assert!(migration.name.starts_with(&format!("{:03}_", migration.version)));
+            assert!(!migration.up.trim().is_empty());
+            assert!(!migration.down.trim().is_empty());
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// diesel 1.x derives and `table!` expand to impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
+use diesel::sql_types::{BigInt, Float4, Integer, Nullable, Text, Varchar};
+use serde_json::Value;
+
+use schema::{api_keys, message_history, messages};
+
+#[derive(Queryable, Clone, Debug)]
+pub struct Message {
+    pub id: i32,
+    pub username: String,
+    pub message: String,
+    pub timestamp: i64,
+    pub edited_at: Option<i64>,
+    pub deleted_at: Option<i64>,
+}
+
+impl Message {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "username": self.username,
+            "message": self.message,
+            "timestamp": self.timestamp,
+            "edited_at": self.edited_at,
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "messages"]
+pub struct NewMessage {
+    pub username: String,
+    pub message: String,
+}
+
+#[derive(Queryable, Clone, Debug)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub is_admin: bool,
+}
+
+#[derive(Queryable, Clone, Debug)]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub key_hash: String,
+    pub revoked: bool,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "api_keys"]
+pub struct NewApiKey<'a> {
+    pub user_id: i32,
+    pub key_hash: &'a str,
+}
+
+/// The text a message had before being edited or deleted.
+#[derive(Queryable, Clone, Debug)]
+pub struct MessageEdit {
+    pub id: i32,
+    pub message_id: i32,
+    /// Either "edit" or "delete".
+    pub action: String,
+    pub message: String,
+    pub edited_by: String,
+    pub edited_at: i64,
+}
+
+impl MessageEdit {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "action": self.action,
+            "message": self.message,
+            "edited_by": self.edited_by,
+            "edited_at": self.edited_at,
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "message_history"]
+pub struct NewMessageEdit<'a> {
+    pub message_id: i32,
+    pub action: &'a str,
+    pub message: &'a str,
+    pub edited_by: &'a str,
+    pub edited_at: i64,
+}
+
+/// A message matched by a full-text search, with its rank and snippet.
+#[derive(QueryableByName)]
+pub struct SearchRow {
+    #[sql_type = "Integer"]
+    pub id: i32,
+    #[sql_type = "Varchar"]
+    pub username: String,
+    #[sql_type = "Text"]
+    pub message: String,
+    #[sql_type = "BigInt"]
+    pub timestamp: i64,
+    #[sql_type = "Nullable<BigInt>"]
+    pub edited_at: Option<i64>,
+    #[sql_type = "Float4"]
+    pub rank: f32,
+    #[sql_type = "Text"]
+    pub snippet: String,
+}
@@ -0,0 +1,445 @@
+use serde_json::Value;
+
+use pagination::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+/// The OpenAPI 3 description of every route, served at `/openapi.json`.
+pub fn document() -> Value {
+    json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "microservice",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/": {
+                "get": {
+                    "summary": "Render a page of messages as HTML, with links to the neighbouring pages",
+                    "parameters": list_parameters(),
+                    "responses": {
+                        "200": {
+                            "description": "The messages, oldest first",
+                            "content": { "text/html": { "schema": { "type": "string" } } },
+                        },
+                        "400": error_response("Invalid parameters"),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/api": {
+                "post": {
+                    "summary": "Post a message as the authenticated user",
+                    "security": [{ "bearer": [] }, { "apiKey": [] }, {}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/x-www-form-urlencoded": {
+                                "schema": {
+                                    "type": "object",
+                                    "required": ["message"],
+                                    "properties": { "message": { "type": "string" } },
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The message was stored",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "required": ["timestamp"],
+                                        "properties": {
+                                            "timestamp": {
+                                                "type": "integer",
+                                                "format": "int64",
+                                                "description": "Seconds since the Unix epoch",
+                                            },
+                                        },
+                                    },
+                                },
+                            },
+                        },
+                        "400": error_response("Missing the message field"),
+                        "401": error_response("Missing or invalid API key"),
+                        "413": error_response("The form is larger than the configured limit"),
+                        "429": rate_limited_response(),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/api/messages": {
+                "get": {
+                    "summary": "List a page of messages",
+                    "parameters": list_parameters(),
+                    "responses": {
+                        "200": {
+                            "description": "The messages, oldest first",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": {
+                                            "messages": {
+                                                "type": "array",
+                                                "items": { "$ref": "#/components/schemas/Message" },
+                                            },
+                                            "next_cursor": {
+                                                "type": "string",
+                                                "nullable": true,
+                                                "description": "Pass as `cursor` to read the next page, if any",
+                                            },
+                                            "prev_cursor": {
+                                                "type": "string",
+                                                "nullable": true,
+                                                "description": "Pass as `cursor` to read the previous page, if any",
+                                            },
+                                        },
+                                    },
+                                },
+                            },
+                        },
+                        "400": error_response("Invalid parameters"),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/api/stream": {
+                "get": {
+                    "summary": "Stream new messages as server-sent events",
+                    "parameters": [{
+                        "name": "Last-Event-ID",
+                        "in": "header",
+                        "description": "Replay the messages posted after this event id first; \
+                                        a bare timestamp replays those after that second",
+                        "schema": { "type": "string" },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "One `message` event per message, with an opaque cursor \
+                                            as the event id and the message JSON as data",
+                            "content": { "text/event-stream": { "schema": { "type": "string" } } },
+                        },
+                        "400": error_response("Invalid Last-Event-ID"),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/api/search": {
+                "get": {
+                    "summary": "Full-text search over messages and usernames",
+                    "parameters": search_parameters(),
+                    "responses": {
+                        "200": {
+                            "description": "The best matches first",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": {
+                                            "results": {
+                                                "type": "array",
+                                                "items": { "$ref": "#/components/schemas/SearchResult" },
+                                            },
+                                        },
+                                    },
+                                },
+                            },
+                        },
+                        "400": error_response("Missing query or invalid parameters"),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/api/messages/{id}": {
+                "parameters": [message_id_parameter()],
+                "patch": {
+                    "summary": "Replace the text of a message, as its author or an admin",
+                    "security": [{ "bearer": [] }, { "apiKey": [] }],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/x-www-form-urlencoded": {
+                                "schema": {
+                                    "type": "object",
+                                    "required": ["message"],
+                                    "properties": { "message": { "type": "string" } },
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The edited message",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/Message" },
+                                },
+                            },
+                        },
+                        "400": error_response("Missing the message field"),
+                        "401": error_response("Missing or invalid API key"),
+                        "403": error_response("Not the author nor an admin"),
+                        "404": error_response("No such message, or it was deleted"),
+                        "413": error_response("The form is larger than the configured limit"),
+                        "429": rate_limited_response(),
+                        "500": error_response("A database failure"),
+                    },
+                },
+                "delete": {
+                    "summary": "Soft-delete a message, as its author or an admin",
+                    "security": [{ "bearer": [] }, { "apiKey": [] }],
+                    "responses": {
+                        "204": { "description": "The message was deleted" },
+                        "401": error_response("Missing or invalid API key"),
+                        "403": error_response("Not the author nor an admin"),
+                        "404": error_response("No such message, or it was already deleted"),
+                        "429": rate_limited_response(),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/api/messages/{id}/history": {
+                "parameters": [message_id_parameter()],
+                "get": {
+                    "summary": "The edits and deletion of a message, as its author or an admin",
+                    "security": [{ "bearer": [] }, { "apiKey": [] }],
+                    "responses": {
+                        "200": {
+                            "description": "The previous texts of the message, oldest first",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": {
+                                            "history": {
+                                                "type": "array",
+                                                "items": { "$ref": "#/components/schemas/MessageEdit" },
+                                            },
+                                        },
+                                    },
+                                },
+                            },
+                        },
+                        "401": error_response("Missing or invalid API key"),
+                        "403": error_response("Not the author nor an admin"),
+                        "404": error_response("No such message"),
+                        "500": error_response("A database failure"),
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": {
+                        "200": {
+                            "description": "The OpenAPI description of the service",
+                            "content": { "application/json": { "schema": { "type": "object" } } },
+                        },
+                    },
+                },
+            },
+            "/healthz": {
+                "get": {
+                    "summary": "Liveness probe",
+                    "responses": {
+                        "200": status_response("The process is serving requests"),
+                    },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness probe, checks the database connection",
+                    "responses": {
+                        "200": status_response("The service can handle traffic"),
+                        "503": status_response("The database cannot be reached"),
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "Message": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "username": { "type": "string" },
+                        "message": { "type": "string" },
+                        "timestamp": { "type": "integer", "format": "int64" },
+                        "edited_at": {
+                            "type": "integer",
+                            "format": "int64",
+                            "nullable": true,
+                            "description": "When the message was last edited, if ever",
+                        },
+                    },
+                },
+                "MessageEdit": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "action": { "type": "string", "enum": ["edit", "delete"] },
+                        "message": { "type": "string", "description": "The text before the change" },
+                        "edited_by": { "type": "string" },
+                        "edited_at": { "type": "integer", "format": "int64" },
+                    },
+                },
+                "SearchResult": {
+                    "allOf": [
+                        { "$ref": "#/components/schemas/Message" },
+                        {
+                            "type": "object",
+                            "properties": {
+                                "rank": { "type": "number" },
+                                "snippet": {
+                                    "type": "string",
+                                    "description": "HTML-escaped excerpt with matches in <mark> tags",
+                                },
+                            },
+                        },
+                    ],
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } },
+                },
+                "Status": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "type": "string" },
+                        "error": { "type": "string" },
+                    },
+                },
+            },
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+            },
+        },
+    })
+}
+
+fn time_range_parameters() -> Value {
+    json!([
+        {
+            "name": "before",
+            "in": "query",
+            "description": "Only messages posted strictly before this timestamp",
+            "schema": { "type": "integer", "format": "int64" },
+        },
+        {
+            "name": "after",
+            "in": "query",
+            "description": "Only messages posted strictly after this timestamp",
+            "schema": { "type": "integer", "format": "int64" },
+        },
+    ])
+}
+
+fn list_parameters() -> Value {
+    let mut parameters = time_range_parameters();
+    parameters.as_array_mut().unwrap().extend(vec![
+        json!({
+            "name": "limit",
+            "in": "query",
+            "description": format!("Messages per page, at most {}", MAX_PAGE_SIZE),
+            "schema": { "type": "integer", "default": DEFAULT_PAGE_SIZE },
+        }),
+        json!({
+            "name": "cursor",
+            "in": "query",
+            "description": "An opaque `next_cursor` or `prev_cursor` from a previous page",
+            "schema": { "type": "string" },
+        }),
+    ]);
+    parameters
+}
+
+fn search_parameters() -> Value {
+    let mut parameters = time_range_parameters();
+    parameters.as_array_mut().unwrap().insert(
+        0,
+        json!({
+            "name": "q",
+            "in": "query",
+            "required": true,
+            "description": "Words that must all appear in the message or username",
+            "schema": { "type": "string" },
+        }),
+    );
+    parameters
+}
+
+fn message_id_parameter() -> Value {
+    json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "integer" },
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+        },
+    })
+}
+
+fn rate_limited_response() -> Value {
+    let mut response = error_response("Too many requests from this address or user");
+    response["headers"] = json!({
+        "Retry-After": {
+            "description": "Seconds to wait before retrying",
+            "schema": { "type": "integer" },
+        },
+    });
+    response
+}
+
+fn status_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": "#/components/schemas/Status" } },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_every_route() {
+        let document = document();
+        let mut paths = document["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            vec![
+                "/",
+                "/api",
+                "/api/messages",
+                "/api/messages/{id}",
+                "/api/messages/{id}/history",
+                "/api/search",
+                "/api/stream",
+                "/healthz",
+                "/openapi.json",
+                "/readyz",
+            ],
+            paths
+        );
+
+        let parameters = document["paths"]["/"]["get"]["parameters"].as_array().unwrap();
+        let names = parameters.iter().map(|parameter| &parameter["name"]).collect::<Vec<_>>();
+        assert_eq!(vec!["before", "after", "limit", "cursor"], names);
+    }
+}
@@ -0,0 +1,246 @@
+use hex;
+use serde_json::Value;
+
+use models::Message;
+
+/// Page size when the request does not give a `limit`.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// A position between two messages in `(timestamp, id)` order, and which way
+/// to read from it. The id breaks ties between messages posted in the same
+/// second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cursor {
+    pub timestamp: i64,
+    pub id: i32,
+    pub direction: Direction,
+}
+
+impl Cursor {
+    pub fn at(message: &Message, direction: Direction) -> Cursor {
+        Cursor {
+            timestamp: message.timestamp,
+            id: message.id,
+            direction,
+        }
+    }
+
+    /// Clients must treat the result as opaque, so the format can change.
+    pub fn encode(&self) -> String {
+        let direction = match self.direction {
+            Direction::Forward => 'f',
+            Direction::Backward => 'b',
+        };
+        hex::encode(format!("{}:{}:{}", direction, self.timestamp, self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Cursor, String> {
+        let invalid = || String::from("Invalid cursor");
+        let bytes = hex::decode(cursor).map_err(|_| invalid())?;
+        let text = String::from_utf8(bytes).map_err(|_| invalid())?;
+        let mut parts = text.splitn(3, ':');
+        let direction = match parts.next() {
+            Some("f") => Direction::Forward,
+            Some("b") => Direction::Backward,
+            _ => return Err(invalid()),
+        };
+        let timestamp = parts.next().and_then(|part| part.parse().ok()).ok_or_else(invalid)?;
+        let id = parts.next().and_then(|part| part.parse().ok()).ok_or_else(invalid)?;
+        Ok(Cursor {
+            timestamp,
+            id,
+            direction,
+        })
+    }
+
+    /// Whether `message` comes strictly after the cursor, reading in its
+    /// direction.
+    pub fn admits(&self, message: &Message) -> bool {
+        let key = (message.timestamp, message.id);
+        match self.direction {
+            Direction::Forward => key > (self.timestamp, self.id),
+            Direction::Backward => key < (self.timestamp, self.id),
+        }
+    }
+}
+
+pub struct PageRequest {
+    pub cursor: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl PageRequest {
+    pub fn direction(&self) -> Direction {
+        self.cursor.map_or(Direction::Forward, |cursor| cursor.direction)
+    }
+}
+
+/// Messages oldest first, with cursors to the neighbouring pages if there
+/// are any.
+#[derive(Debug)]
+pub struct Page {
+    pub messages: Vec<Message>,
+    pub next: Option<Cursor>,
+    pub prev: Option<Cursor>,
+}
+
+impl Page {
+    /// Builds a page from up to `limit + 1` rows read in the direction of the
+    /// request; the extra row only tells that there is more to read.
+    pub fn from_rows(mut rows: Vec<Message>, request: &PageRequest) -> Page {
+        let has_more = rows.len() > request.limit;
+        rows.truncate(request.limit);
+
+        let direction = request.direction();
+        if direction == Direction::Backward {
+            rows.reverse();
+        }
+        // Coming from a cursor means there is a page on the side it came from.
+        let came_from_cursor = request.cursor.is_some();
+        let (more_after, more_before) = match direction {
+            Direction::Forward => (has_more, came_from_cursor),
+            Direction::Backward => (came_from_cursor, has_more),
+        };
+
+        Page {
+            next: rows
+                .last()
+                .filter(|_| more_after)
+                .map(|message| Cursor::at(message, Direction::Forward)),
+            prev: rows
+                .first()
+                .filter(|_| more_before)
+                .map(|message| Cursor::at(message, Direction::Backward)),
+            messages: rows,
+        }
+    }
+
+    /// Pages through messages held in memory, in any order.
+    pub fn of(messages: Vec<Message>, request: &PageRequest) -> Page {
+        let mut rows = messages
+            .into_iter()
+            .filter(|message| request.cursor.is_none_or(|cursor| cursor.admits(message)))
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|message| (message.timestamp, message.id));
+        if request.direction() == Direction::Backward {
+            rows.reverse();
+        }
+        rows.truncate(request.limit + 1);
+        Page::from_rows(rows, request)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "messages": self.messages.iter().map(Message::to_json).collect::<Vec<_>>(),
+            "next_cursor": self.next.map(|cursor| cursor.encode()),
+            "prev_cursor": self.prev.map(|cursor| cursor.encode()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Four messages, the middle two posted in the same second, out of order
+    /// as in a store where timestamps are not assigned by id.
+    fn messages() -> Vec<Message> {
+        [(4, 300), (1, 100), (3, 200), (2, 200)]
+            .iter()
+            .map(|&(id, timestamp)| Message {
+                id,
+                username: String::from("alice"),
+                message: format!("message {}", id),
+                timestamp,
+                edited_at: None,
+                deleted_at: None,
+            })
+            .collect()
+    }
+
+    fn read(messages: &[Message], request: &PageRequest) -> Page {
+        Page::of(messages.to_vec(), request)
+    }
+
+    fn ids(page: &Page) -> Vec<i32> {
+        page.messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            timestamp: 1_520_000_000,
+            id: 42,
+            direction: Direction::Backward,
+        };
+        assert_eq!(Ok(cursor), Cursor::decode(&cursor.encode()));
+        assert!(Cursor::decode("zz").is_err());
+        assert!(Cursor::decode(&hex::encode("x:1:2")).is_err());
+        assert!(Cursor::decode(&hex::encode("f:1")).is_err());
+    }
+
+    #[test]
+    fn pages_split_ties() {
+        let messages = messages();
+        let first = read(&messages, &PageRequest { cursor: None, limit: 2 });
+        assert_eq!(vec![1, 2], ids(&first));
+        assert_eq!(None, first.prev);
+
+        let second = read(&messages, &PageRequest { cursor: first.next, limit: 2 });
+        assert_eq!(vec![3, 4], ids(&second));
+        assert_eq!(None, second.next);
+
+        let back = read(&messages, &PageRequest { cursor: second.prev, limit: 2 });
+        assert_eq!(vec![1, 2], ids(&back));
+        assert_eq!(None, back.prev);
+        assert_eq!(second.prev.map(|cursor| cursor.id), Some(3));
+        assert_eq!(back.next.map(|cursor| cursor.id), Some(2));
+    }
+
+    #[test]
+    fn backward_pages_stay_oldest_first() {
+        let messages = messages();
+        let end = Cursor {
+            timestamp: 300,
+            id: 4,
+            direction: Direction::Backward,
+        };
+        let page = read(&messages, &PageRequest { cursor: Some(end), limit: 2 });
+        assert_eq!(vec![2, 3], ids(&page));
+        assert_eq!(Some(1), read(&messages, &PageRequest { cursor: page.prev, limit: 2 })
+            .messages
+            .first()
+            .map(|message| message.id));
+    }
+
+    #[test]
+    fn empty_pages_have_no_cursors() {
+        let page = read(&[], &PageRequest { cursor: None, limit: 2 });
+        assert!(page.messages.is_empty());
+        assert_eq!((None, None), (page.next, page.prev));
+
+        let past_the_end = Cursor {
+            timestamp: 300,
+            id: 4,
+            direction: Direction::Forward,
+        };
+        let page = read(&messages(), &PageRequest { cursor: Some(past_the_end), limit: 2 });
+        assert!(page.messages.is_empty());
+        assert_eq!((None, None), (page.next, page.prev));
+        assert_eq!(json!(null), page.to_json()["next_cursor"]);
+    }
+
+    #[test]
+    fn exact_fit_has_no_next_page() {
+        let page = read(&messages(), &PageRequest { cursor: None, limit: 4 });
+        assert_eq!(vec![1, 2, 3, 4], ids(&page));
+        assert_eq!(None, page.next);
+    }
+}
@@ -0,0 +1,54 @@
+// diesel 1.x derives and `table!` expand to impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
+table! {
+    messages (id) {
+        id -> Int4,
+        username -> Varchar,
+        message -> Text,
+        timestamp -> Int8,
+        edited_at -> Nullable<Int8>,
+        deleted_at -> Nullable<Int8>,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Int4,
+        username -> Varchar,
+        is_admin -> Bool,
+    }
+}
+
+table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        key_hash -> Varchar,
+        revoked -> Bool,
+    }
+}
+
+table! {
+    message_history (id) {
+        id -> Int4,
+        message_id -> Int4,
+        action -> Varchar,
+        message -> Text,
+        edited_by -> Varchar,
+        edited_at -> Int8,
+    }
+}
+
+joinable!(api_keys -> users (user_id));
+joinable!(message_history -> messages (message_id));
+
+allow_tables_to_appear_in_same_query!(api_keys, users);
+allow_tables_to_appear_in_same_query!(message_history, messages);
+
+table! {
+    schema_migrations (version) {
+        version -> Int4,
+        applied_at -> Int8,
+    }
+}
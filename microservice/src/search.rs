@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use models::Message;
+
+/// Most results returned by a single search.
+pub const SEARCH_LIMIT: i64 = 50;
+
+/// Number of words shown in a snippet.
+pub const SNIPPET_WORDS: usize = 20;
+
+/// Surround highlighted terms in snippets coming from a backend, so that
+/// the text can be escaped before the markers are turned into `<mark>` tags.
+pub const HIGHLIGHT_START: char = '\u{2}';
+pub const HIGHLIGHT_STOP: char = '\u{3}';
+
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub message: Message,
+    pub rank: f32,
+    /// HTML-escaped excerpt of the message with matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+/// Inverted index over message and username words, used by backends without
+/// full-text search of their own.
+#[derive(Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, HashMap<i32, u32>>,
+    documents: usize,
+}
+
+impl SearchIndex {
+    pub fn add(&mut self, message: &Message) {
+        let document = format!("{} {}", message.username, message.message);
+        for term in tokenize(&document) {
+            *self
+                .postings
+                .entry(term)
+                .or_default()
+                .entry(message.id)
+                .or_insert(0) += 1;
+        }
+        self.documents += 1;
+    }
+
+    pub fn remove(&mut self, message: &Message) {
+        let document = format!("{} {}", message.username, message.message);
+        for term in tokenize(&document) {
+            let now_empty = match self.postings.get_mut(&term) {
+                Some(documents) => {
+                    documents.remove(&message.id);
+                    documents.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                self.postings.remove(&term);
+            }
+        }
+        self.documents -= 1;
+    }
+
+    /// Returns the ids of the messages containing every term, with a tf-idf
+    /// score, in no particular order.
+    pub fn search(&self, terms: &[String]) -> Vec<(i32, f32)> {
+        let mut postings = Vec::with_capacity(terms.len());
+        for term in terms {
+            match self.postings.get(term) {
+                Some(documents) => postings.push(documents),
+                None => return Vec::new(),
+            }
+        }
+        let (first, rest) = match postings.split_first() {
+            Some(split) => split,
+            None => return Vec::new(),
+        };
+
+        first
+            .keys()
+            .filter(|id| rest.iter().all(|documents| documents.contains_key(id)))
+            .map(|id| {
+                let rank = postings
+                    .iter()
+                    .map(|documents| {
+                        let idf = (1.0 + self.documents as f32 / documents.len() as f32).ln();
+                        documents[id] as f32 * idf
+                    })
+                    .sum();
+                (*id, rank)
+            })
+            .collect()
+    }
+}
+
+/// Splits text into lowercase alphanumeric words, without duplicates.
+pub fn query_terms(query: &str) -> Vec<String> {
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    words(text)
+        .into_iter()
+        .map(|(start, end)| text[start..end].to_lowercase())
+        .collect()
+}
+
+/// Byte ranges of the alphanumeric words in `text`.
+fn words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (index, character) in text.char_indices() {
+        match (character.is_alphanumeric(), start) {
+            (true, None) => start = Some(index),
+            (false, Some(word_start)) => {
+                words.push((word_start, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(word_start) = start {
+        words.push((word_start, text.len()));
+    }
+    words
+}
+
+/// Builds a snippet of `text` around the first word matching one of `terms`,
+/// with the matches marked by `HIGHLIGHT_START` and `HIGHLIGHT_STOP`.
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let words = words(text);
+    let is_match = |&(start, end): &(usize, usize)| terms.contains(&text[start..end].to_lowercase());
+
+    let first_match = words.iter().position(is_match).unwrap_or(0);
+    let first = first_match.saturating_sub(SNIPPET_WORDS / 4);
+    let last = (first + SNIPPET_WORDS).min(words.len());
+
+    let mut snippet = String::new();
+    let mut position = if first == 0 { 0 } else { words[first].0 };
+    if first > 0 {
+        snippet.push_str("...");
+    }
+    for word in &words[first..last] {
+        snippet.push_str(&text[position..word.0]);
+        if is_match(word) {
+            snippet.push(HIGHLIGHT_START);
+            snippet.push_str(&text[word.0..word.1]);
+            snippet.push(HIGHLIGHT_STOP);
+        } else {
+            snippet.push_str(&text[word.0..word.1]);
+        }
+        position = word.1;
+    }
+    if last == words.len() {
+        snippet.push_str(&text[position..]);
+    } else {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+/// Escapes a marked snippet for HTML and turns its markers into `<mark>` tags.
+pub fn highlight(snippet: &str) -> String {
+    ::escape_html(snippet)
+        .replace(HIGHLIGHT_START, "<mark>")
+        .replace(HIGHLIGHT_STOP, "</mark>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32, username: &str, message: &str) -> Message {
+        Message {
+            id,
+            username: username.to_string(),
+            message: message.to_string(),
+            timestamp: 0,
+            edited_at: None,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn index_requires_every_term() {
+        let mut index = SearchIndex::default();
+        index.add(&message(1, "alice", "Deploy is done"));
+        index.add(&message(2, "bob", "deploy failed again"));
+
+        let mut ids = index
+            .search(&query_terms("DEPLOY"))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(vec![1, 2], ids);
+
+        let hits = index.search(&query_terms("deploy bob"));
+        assert_eq!(1, hits.len());
+        assert_eq!(2, hits[0].0);
+
+        assert!(index.search(&query_terms("rollback")).is_empty());
+        assert!(index.search(&query_terms("  ")).is_empty());
+    }
+
+    #[test]
+    fn index_forgets_removed_messages() {
+        let mut index = SearchIndex::default();
+        let first = message(1, "alice", "typo here");
+        index.add(&first);
+        index.add(&message(2, "bob", "typo there"));
+        index.remove(&first);
+
+        let hits = index.search(&query_terms("typo"));
+        assert_eq!(1, hits.len());
+        assert_eq!(2, hits[0].0);
+        assert!(index.search(&query_terms("here")).is_empty());
+    }
+
+    #[test]
+    fn index_ranks_repeated_terms_higher() {
+        let mut index = SearchIndex::default();
+        index.add(&message(1, "alice", "cake"));
+        index.add(&message(2, "bob", "cake cake cake"));
+
+        let hits = index.search(&query_terms("cake"));
+        let rank = |id| hits.iter().find(|hit| hit.0 == id).unwrap().1;
+        assert!(rank(2) > rank(1));
+    }
+
+    #[test]
+    fn snippet_highlights_and_escapes() {
+        let terms = query_terms("rust");
+        let marked = snippet("<b>Rust</b> is fun, rust!", &terms);
+        assert_eq!(
+            "&lt;b&gt;<mark>Rust</mark>&lt;/b&gt; is fun, <mark>rust</mark>!",
+            highlight(&marked)
+        );
+    }
+
+    #[test]
+    fn snippet_is_cut_around_the_first_match() {
+        let text = (0..100).map(|n| format!("w{}", n)).collect::<Vec<_>>().join(" ");
+        let marked = snippet(&text, &query_terms("w50"));
+        assert!(marked.starts_with("...w45 "));
+        assert!(marked.ends_with(" w64..."));
+        assert!(highlight(&marked).contains("<mark>w50</mark>"));
+    }
+}
@@ -0,0 +1,652 @@
+use diesel;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool, PoolError};
+use diesel::sql_types::{BigInt, Nullable, Text};
+
+use std::fmt;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use auth;
+use config::Config;
+use migrations;
+use pagination::{Direction, Page, PageRequest};
+use models::{ApiKey, Message, MessageEdit, NewApiKey, NewMessage, NewMessageEdit, SearchRow, User};
+use search::{self, SearchIndex, SearchResult, HIGHLIGHT_START, HIGHLIGHT_STOP, SEARCH_LIMIT,
+             SNIPPET_WORDS};
+use TimeRange;
+
+#[derive(Debug)]
+pub struct StoreError(String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for StoreError {}
+
+impl From<String> for StoreError {
+    fn from(message: String) -> StoreError {
+        StoreError(message)
+    }
+}
+
+impl<'a> From<&'a str> for StoreError {
+    fn from(message: &'a str) -> StoreError {
+        StoreError(message.to_string())
+    }
+}
+
+impl From<diesel::result::Error> for StoreError {
+    fn from(error: diesel::result::Error) -> StoreError {
+        StoreError(error.to_string())
+    }
+}
+
+impl From<PoolError> for StoreError {
+    fn from(error: PoolError) -> StoreError {
+        StoreError(format!("Error connecting to database: {}", error))
+    }
+}
+
+/// Persistence for chat messages.
+///
+/// Messages are always returned ordered by timestamp, oldest first. Deleted
+/// messages are kept, but only show up in their history.
+pub trait MessageStore: Send + Sync {
+    fn insert(&self, entry: NewMessage) -> Result<Message, StoreError>;
+    fn query(&self, time_range: &TimeRange) -> Result<Vec<Message>, StoreError>;
+    /// One page of the messages in `time_range`, oldest first.
+    fn page(&self, time_range: &TimeRange, request: &PageRequest) -> Result<Page, StoreError>;
+    /// Full-text search over usernames and messages, best matches first.
+    fn search(&self, query: &str, time_range: &TimeRange) -> Result<Vec<SearchResult>, StoreError>;
+    /// Returns a message, even a deleted one.
+    fn find(&self, id: i32) -> Result<Option<Message>, StoreError>;
+    /// Replaces the text of a message, recording the previous one in its
+    /// history. Returns `None` if there is no such message.
+    fn edit(&self, id: i32, text: &str, editor: &str) -> Result<Option<Message>, StoreError>;
+    /// Marks a message as deleted, recording its text in its history. Returns
+    /// whether there was such a message.
+    fn delete(&self, id: i32, editor: &str) -> Result<bool, StoreError>;
+    /// The edits and deletion of a message, oldest first.
+    fn history(&self, id: i32) -> Result<Vec<MessageEdit>, StoreError>;
+
+    /// Checks that the backend can serve queries.
+    fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Users and the API keys they authenticate with.
+///
+/// Keys are only ever handled as hashes, see `auth::hash_key`.
+pub trait UserStore: Send + Sync {
+    /// Returns the owner of a key that has not been revoked.
+    fn find_user_by_key(&self, key_hash: &str) -> Result<Option<User>, StoreError>;
+    /// Registers a new key for `username`, creating the user if needed.
+    fn create_key(&self, username: &str, key_hash: &str) -> Result<ApiKey, StoreError>;
+    /// Returns whether a key with this id existed.
+    fn revoke_key(&self, id: i32) -> Result<bool, StoreError>;
+    /// Admins may edit and delete every message. Returns whether the user
+    /// exists.
+    fn set_admin(&self, username: &str, is_admin: bool) -> Result<bool, StoreError>;
+}
+
+pub trait Store: MessageStore + UserStore {}
+
+impl<T: MessageStore + UserStore> Store for T {}
+
+/// Picks a backend from the config: Postgres when a database URL is set, an
+/// in-memory store otherwise.
+///
+/// Pending migrations are applied to Postgres when `auto_migrate` is set;
+/// either way the schema must end up at the latest known version.
+pub fn open(config: &Config) -> Result<Box<dyn Store>, StoreError> {
+    match config.database_url {
+        Some(ref database_url) => {
+            let pool = connect_pool(database_url, config.pool_size)?;
+            {
+                let connection = pool.get()?;
+                if config.auto_migrate {
+                    migrations::run_pending(&connection)?;
+                }
+                migrations::check(&connection)?;
+            }
+            Ok(Box::new(PgStore::new(pool)))
+        }
+        None => {
+            warn!("No database URL is set, messages will only be kept in memory");
+            Ok(Box::new(MemoryStore::new()))
+        }
+    }
+}
+
+fn check_username(username: &str) -> Result<(), StoreError> {
+    if username == auth::ANONYMOUS {
+        return Err(StoreError::from(format!("The username '{}' is reserved", username)));
+    }
+    Ok(())
+}
+
+fn now() -> Result<i64, StoreError> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .map_err(|error| StoreError(error.to_string()))
+}
+
+pub fn establish(database_url: &str) -> Result<PgConnection, StoreError> {
+    PgConnection::establish(database_url)
+        .map_err(|error| StoreError(format!("Error connecting to database: {}", error)))
+}
+
+/// Opens up to `size` connections, failing quickly if there is no database
+/// to connect to.
+fn connect_pool(database_url: &str, size: u32) -> Result<Pool<ConnectionManager<PgConnection>>, StoreError> {
+    Pool::builder()
+        .max_size(size)
+        .connection_timeout(Duration::from_secs(5))
+        .build(ConnectionManager::new(database_url))
+        .map_err(StoreError::from)
+}
+
+/// The current time as computed by the database, like the default of
+/// `messages.timestamp`.
+fn pg_now(connection: &PgConnection) -> Result<i64, StoreError> {
+    let now = diesel::select(diesel::dsl::sql::<BigInt>(
+        "EXTRACT('epoch' FROM CURRENT_TIMESTAMP)::BIGINT",
+    )).get_result(connection)?;
+    Ok(now)
+}
+
+pub struct PgStore {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl PgStore {
+    pub fn new(pool: Pool<ConnectionManager<PgConnection>>) -> PgStore {
+        PgStore { pool }
+    }
+
+    /// Connects to a database that must already be fully migrated.
+    pub fn connect(database_url: &str, pool_size: u32) -> Result<PgStore, StoreError> {
+        let pool = connect_pool(database_url, pool_size)?;
+        migrations::check(&*pool.get()?)?;
+        Ok(PgStore::new(pool))
+    }
+}
+
+impl MessageStore for PgStore {
+    fn insert(&self, entry: NewMessage) -> Result<Message, StoreError> {
+        use schema::messages;
+
+        let connection = self.pool.get()?;
+        let message = diesel::insert_into(messages::table)
+            .values(&entry)
+            .get_result(&*connection)?;
+        Ok(message)
+    }
+
+    fn query(&self, time_range: &TimeRange) -> Result<Vec<Message>, StoreError> {
+        use schema::messages;
+
+        let mut query = messages::table
+            .filter(messages::deleted_at.is_null())
+            .into_boxed();
+        if let Some(before) = time_range.before {
+            query = query.filter(messages::timestamp.lt(before));
+        }
+        if let Some(after) = time_range.after {
+            query = query.filter(messages::timestamp.gt(after));
+        }
+
+        let connection = self.pool.get()?;
+        let messages = query
+            .order((messages::timestamp.asc(), messages::id.asc()))
+            .load::<Message>(&*connection)?;
+        Ok(messages)
+    }
+
+    fn page(&self, time_range: &TimeRange, request: &PageRequest) -> Result<Page, StoreError> {
+        use schema::messages;
+
+        let mut query = messages::table
+            .filter(messages::deleted_at.is_null())
+            .into_boxed();
+        if let Some(before) = time_range.before {
+            query = query.filter(messages::timestamp.lt(before));
+        }
+        if let Some(after) = time_range.after {
+            query = query.filter(messages::timestamp.gt(after));
+        }
+        if let Some(cursor) = request.cursor {
+            query = match cursor.direction {
+                Direction::Forward => query.filter(
+                    messages::timestamp.gt(cursor.timestamp).or(messages::timestamp
+                        .eq(cursor.timestamp)
+                        .and(messages::id.gt(cursor.id))),
+                ),
+                Direction::Backward => query.filter(
+                    messages::timestamp.lt(cursor.timestamp).or(messages::timestamp
+                        .eq(cursor.timestamp)
+                        .and(messages::id.lt(cursor.id))),
+                ),
+            };
+        }
+        query = match request.direction() {
+            Direction::Forward => query.order((messages::timestamp.asc(), messages::id.asc())),
+            Direction::Backward => query.order((messages::timestamp.desc(), messages::id.desc())),
+        };
+
+        let connection = self.pool.get()?;
+        let rows = query
+            .limit(request.limit as i64 + 1)
+            .load::<Message>(&*connection)?;
+        Ok(Page::from_rows(rows, request))
+    }
+
+    fn search(&self, query: &str, time_range: &TimeRange) -> Result<Vec<SearchResult>, StoreError> {
+        let options = format!(
+            "StartSel={}, StopSel={}, MaxWords={}, MinWords=5",
+            HIGHLIGHT_START, HIGHLIGHT_STOP, SNIPPET_WORDS
+        );
+
+        let connection = self.pool.get()?;
+        let rows = diesel::sql_query(
+            "SELECT id, username, message, timestamp, edited_at, \
+                    ts_rank(document, query) AS rank, \
+                    ts_headline('english', message, query, $2) AS snippet \
+             FROM messages, \
+                  to_tsvector('english', username || ' ' || message) document, \
+                  plainto_tsquery('english', $1) query \
+             WHERE document @@ query AND deleted_at IS NULL \
+               AND ($3::BIGINT IS NULL OR timestamp < $3) \
+               AND ($4::BIGINT IS NULL OR timestamp > $4) \
+             ORDER BY rank DESC, timestamp DESC \
+             LIMIT $5",
+        ).bind::<Text, _>(query)
+            .bind::<Text, _>(options)
+            .bind::<Nullable<BigInt>, _>(time_range.before)
+            .bind::<Nullable<BigInt>, _>(time_range.after)
+            .bind::<BigInt, _>(SEARCH_LIMIT)
+            .load::<SearchRow>(&*connection)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchResult {
+                message: Message {
+                    id: row.id,
+                    username: row.username,
+                    message: row.message,
+                    timestamp: row.timestamp,
+                    edited_at: row.edited_at,
+                    deleted_at: None,
+                },
+                rank: row.rank,
+                snippet: search::highlight(&row.snippet),
+            })
+            .collect())
+    }
+
+    fn find(&self, id: i32) -> Result<Option<Message>, StoreError> {
+        use schema::messages;
+
+        let connection = self.pool.get()?;
+        let message = messages::table
+            .find(id)
+            .first::<Message>(&*connection)
+            .optional()?;
+        Ok(message)
+    }
+
+    fn edit(&self, id: i32, text: &str, editor: &str) -> Result<Option<Message>, StoreError> {
+        use schema::{message_history, messages};
+
+        let connection = self.pool.get()?;
+        connection.transaction::<_, StoreError, _>(|| {
+            let edited_at = pg_now(&connection)?;
+            let current = messages::table
+                .find(id)
+                .filter(messages::deleted_at.is_null())
+                .for_update()
+                .first::<Message>(&*connection)
+                .optional()?;
+            let current = match current {
+                Some(current) => current,
+                None => return Ok(None),
+            };
+            diesel::insert_into(message_history::table)
+                .values(&NewMessageEdit {
+                    message_id: id,
+                    action: "edit",
+                    message: &current.message,
+                    edited_by: editor,
+                    edited_at,
+                })
+                .execute(&*connection)?;
+            let message = diesel::update(messages::table.find(id))
+                .set((messages::message.eq(text), messages::edited_at.eq(edited_at)))
+                .get_result::<Message>(&*connection)?;
+            Ok(Some(message))
+        })
+    }
+
+    fn delete(&self, id: i32, editor: &str) -> Result<bool, StoreError> {
+        use schema::{message_history, messages};
+
+        let connection = self.pool.get()?;
+        connection.transaction::<_, StoreError, _>(|| {
+            let deleted_at = pg_now(&connection)?;
+            let current = messages::table
+                .find(id)
+                .filter(messages::deleted_at.is_null())
+                .for_update()
+                .first::<Message>(&*connection)
+                .optional()?;
+            let current = match current {
+                Some(current) => current,
+                None => return Ok(false),
+            };
+            diesel::insert_into(message_history::table)
+                .values(&NewMessageEdit {
+                    message_id: id,
+                    action: "delete",
+                    message: &current.message,
+                    edited_by: editor,
+                    edited_at: deleted_at,
+                })
+                .execute(&*connection)?;
+            diesel::update(messages::table.find(id))
+                .set(messages::deleted_at.eq(deleted_at))
+                .execute(&*connection)?;
+            Ok(true)
+        })
+    }
+
+    fn history(&self, id: i32) -> Result<Vec<MessageEdit>, StoreError> {
+        use schema::message_history;
+
+        let connection = self.pool.get()?;
+        let history = message_history::table
+            .filter(message_history::message_id.eq(id))
+            .order(message_history::id.asc())
+            .load::<MessageEdit>(&*connection)?;
+        Ok(history)
+    }
+
+    fn ping(&self) -> Result<(), StoreError> {
+        let connection = self.pool.get()?;
+        diesel::sql_query("SELECT 1").execute(&*connection)?;
+        Ok(())
+    }
+}
+
+impl UserStore for PgStore {
+    fn find_user_by_key(&self, key_hash: &str) -> Result<Option<User>, StoreError> {
+        use schema::{api_keys, users};
+
+        let connection = self.pool.get()?;
+        let user = api_keys::table
+            .inner_join(users::table)
+            .filter(api_keys::key_hash.eq(key_hash))
+            .filter(api_keys::revoked.eq(false))
+            .select(users::all_columns)
+            .first::<User>(&*connection)
+            .optional()?;
+        Ok(user)
+    }
+
+    fn create_key(&self, username: &str, key_hash: &str) -> Result<ApiKey, StoreError> {
+        check_username(username)?;
+        use schema::{api_keys, users};
+
+        let connection = self.pool.get()?;
+        let key = connection.transaction::<_, diesel::result::Error, _>(|| {
+            let existing = users::table
+                .filter(users::username.eq(username))
+                .first::<User>(&*connection)
+                .optional()?;
+            let user = match existing {
+                Some(user) => user,
+                None => diesel::insert_into(users::table)
+                    .values(users::username.eq(username))
+                    .get_result::<User>(&*connection)?,
+            };
+            diesel::insert_into(api_keys::table)
+                .values(&NewApiKey {
+                    user_id: user.id,
+                    key_hash,
+                })
+                .get_result::<ApiKey>(&*connection)
+        })?;
+        Ok(key)
+    }
+
+    fn revoke_key(&self, id: i32) -> Result<bool, StoreError> {
+        use schema::api_keys;
+
+        let connection = self.pool.get()?;
+        let updated = diesel::update(api_keys::table.find(id))
+            .set(api_keys::revoked.eq(true))
+            .execute(&*connection)?;
+        Ok(updated > 0)
+    }
+
+    fn set_admin(&self, username: &str, is_admin: bool) -> Result<bool, StoreError> {
+        use schema::users;
+
+        let connection = self.pool.get()?;
+        let updated = diesel::update(users::table.filter(users::username.eq(username)))
+            .set(users::is_admin.eq(is_admin))
+            .execute(&*connection)?;
+        Ok(updated > 0)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    messages: Mutex<Vec<Message>>,
+    index: Mutex<SearchIndex>,
+    history: Mutex<Vec<MessageEdit>>,
+    users: Mutex<Vec<User>>,
+    api_keys: Mutex<Vec<ApiKey>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn insert(&self, entry: NewMessage) -> Result<Message, StoreError> {
+        let timestamp = now()?;
+        let mut messages = self.messages.lock().unwrap();
+        let message = Message {
+            id: messages.len() as i32 + 1,
+            username: entry.username,
+            message: entry.message,
+            timestamp,
+            edited_at: None,
+            deleted_at: None,
+        };
+        messages.push(message.clone());
+        self.index.lock().unwrap().add(&message);
+        Ok(message)
+    }
+
+    fn query(&self, time_range: &TimeRange) -> Result<Vec<Message>, StoreError> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .iter()
+            .filter(|message| message.deleted_at.is_none())
+            .filter(|message| time_range.contains(message.timestamp))
+            .cloned()
+            .collect())
+    }
+
+    fn page(&self, time_range: &TimeRange, request: &PageRequest) -> Result<Page, StoreError> {
+        let messages = self.query(time_range)?;
+        Ok(Page::of(messages, request))
+    }
+
+    fn search(&self, query: &str, time_range: &TimeRange) -> Result<Vec<SearchResult>, StoreError> {
+        let terms = search::query_terms(query);
+        let messages = self.messages.lock().unwrap();
+        let hits = self.index.lock().unwrap().search(&terms);
+
+        let mut results = hits
+            .into_iter()
+            .map(|(id, rank)| (&messages[id as usize - 1], rank))
+            .filter(|&(message, _)| time_range.contains(message.timestamp))
+            .map(|(message, rank)| SearchResult {
+                message: message.clone(),
+                rank,
+                snippet: search::highlight(&search::snippet(&message.message, &terms)),
+            })
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| {
+            b.rank
+                .partial_cmp(&a.rank)
+                .unwrap()
+                .then(b.message.timestamp.cmp(&a.message.timestamp))
+        });
+        results.truncate(SEARCH_LIMIT as usize);
+        Ok(results)
+    }
+
+    fn find(&self, id: i32) -> Result<Option<Message>, StoreError> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages.iter().find(|message| message.id == id).cloned())
+    }
+
+    fn edit(&self, id: i32, text: &str, editor: &str) -> Result<Option<Message>, StoreError> {
+        let edited_at = now()?;
+        let mut messages = self.messages.lock().unwrap();
+        let message = match messages
+            .iter_mut()
+            .find(|message| message.id == id && message.deleted_at.is_none())
+        {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let mut index = self.index.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        index.remove(message);
+        let previous = ::std::mem::replace(&mut message.message, text.to_string());
+        message.edited_at = Some(edited_at);
+        index.add(message);
+        let edit_id = history.len() as i32 + 1;
+        history.push(MessageEdit {
+            id: edit_id,
+            message_id: id,
+            action: String::from("edit"),
+            message: previous,
+            edited_by: editor.to_string(),
+            edited_at,
+        });
+        Ok(Some(message.clone()))
+    }
+
+    fn delete(&self, id: i32, editor: &str) -> Result<bool, StoreError> {
+        let deleted_at = now()?;
+        let mut messages = self.messages.lock().unwrap();
+        let message = match messages
+            .iter_mut()
+            .find(|message| message.id == id && message.deleted_at.is_none())
+        {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+
+        let mut index = self.index.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        index.remove(message);
+        message.deleted_at = Some(deleted_at);
+        let edit_id = history.len() as i32 + 1;
+        history.push(MessageEdit {
+            id: edit_id,
+            message_id: id,
+            action: String::from("delete"),
+            message: message.message.clone(),
+            edited_by: editor.to_string(),
+            edited_at: deleted_at,
+        });
+        Ok(true)
+    }
+
+    fn history(&self, id: i32) -> Result<Vec<MessageEdit>, StoreError> {
+        let history = self.history.lock().unwrap();
+        Ok(history
+            .iter()
+            .filter(|edit| edit.message_id == id)
+            .cloned()
+            .collect())
+    }
+}
+
+impl UserStore for MemoryStore {
+    fn find_user_by_key(&self, key_hash: &str) -> Result<Option<User>, StoreError> {
+        let api_keys = self.api_keys.lock().unwrap();
+        let users = self.users.lock().unwrap();
+        Ok(api_keys
+            .iter()
+            .find(|key| key.key_hash == key_hash && !key.revoked)
+            .and_then(|key| users.iter().find(|user| user.id == key.user_id))
+            .cloned())
+    }
+
+    fn create_key(&self, username: &str, key_hash: &str) -> Result<ApiKey, StoreError> {
+        check_username(username)?;
+        let mut api_keys = self.api_keys.lock().unwrap();
+        let mut users = self.users.lock().unwrap();
+
+        let user_id = match users.iter().find(|user| user.username == username) {
+            Some(user) => user.id,
+            None => {
+                let user = User {
+                    id: users.len() as i32 + 1,
+                    username: username.to_string(),
+                    is_admin: false,
+                };
+                users.push(user.clone());
+                user.id
+            }
+        };
+        let key = ApiKey {
+            id: api_keys.len() as i32 + 1,
+            user_id,
+            key_hash: key_hash.to_string(),
+            revoked: false,
+        };
+        api_keys.push(key.clone());
+        Ok(key)
+    }
+
+    fn revoke_key(&self, id: i32) -> Result<bool, StoreError> {
+        let mut api_keys = self.api_keys.lock().unwrap();
+        match api_keys.iter_mut().find(|key| key.id == id) {
+            Some(key) => {
+                key.revoked = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn set_admin(&self, username: &str, is_admin: bool) -> Result<bool, StoreError> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|user| user.username == username) {
+            Some(user) => {
+                user.is_admin = is_admin;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
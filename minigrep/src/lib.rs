@@ -1,18 +1,37 @@
+extern crate regex;
+extern crate termcolor;
+
 use std::env;
 use std::fs::File;
+use std::io::{self, IsTerminal};
 use std::io::prelude::*;
 use std::error::Error;
 
+use regex::{Regex, RegexBuilder};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
 pub struct Config {
     pub query: String,
     pub filename: String,
     pub case_sensitive: bool,
+    /// Whether the query is a regular expression rather than plain text.
+    pub regex: bool,
 }
 
 impl Config {
-    pub fn new(mut args: std::env::Args) -> Result<Config, &'static str> {
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<Config, &'static str> {
         args.next();
 
+        let mut regex = false;
+        let mut positional = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "-e" | "--regex" => regex = true,
+                _ => positional.push(arg),
+            }
+        }
+        let mut args = positional.into_iter();
+
         let query = match args.next() {
             Some(arg) => arg,
             None => return Err("Didn't get a query string"),
@@ -25,42 +44,84 @@ impl Config {
 
         let case_sensitive = env::var("CASE_INSENSITIVE").is_err();
 
-        Ok(Config { query, filename, case_sensitive })
+        Ok(Config { query, filename, case_sensitive, regex })
     }
 }
 
-pub fn run(config: Config) -> Result<(), Box<Error>> {
-    let mut f = File::open(config.filename)?;
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let mut f = File::open(&config.filename)?;
 
     let mut contents = String::new();
     f.read_to_string(&mut contents)?;
 
-    let results = if config.case_sensitive {
+    let pattern = pattern(&config)?;
+    let results = if config.regex {
+        search_regex(&pattern, &contents)
+    } else if config.case_sensitive {
         search(&config.query, &contents)
     } else {
         search_case_insensitive(&config.query, &contents)
     };
 
+    // Colors only make sense on a terminal, not in a pipe or a file.
+    let choice = if io::stdout().is_terminal() { ColorChoice::Auto } else { ColorChoice::Never };
+    let stdout = StandardStream::stdout(choice);
+    let mut out = stdout.lock();
     for line in results {
-        println!("{}", line);
+        print_highlighted(&mut out, line, &pattern)?;
     }
 
     Ok(())
 }
 
+/// The regex that finds what `config` searches for: the query itself with
+/// `--regex`, otherwise the query taken literally.
+pub fn pattern(config: &Config) -> Result<Regex, regex::Error> {
+    let pattern = if config.regex {
+        config.query.clone()
+    } else {
+        regex::escape(&config.query)
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!config.case_sensitive)
+        .build()
+}
+
+/// Writes `line` with the matches of `pattern` in bold red, like grep.
+pub fn print_highlighted<W: WriteColor>(out: &mut W, line: &str, pattern: &Regex) -> io::Result<()> {
+    let mut highlight = ColorSpec::new();
+    highlight.set_fg(Some(Color::Red)).set_bold(true);
+
+    let mut last = 0;
+    for found in pattern.find_iter(line).filter(|found| !found.as_str().is_empty()) {
+        write!(out, "{}", &line[last..found.start()])?;
+        out.set_color(&highlight)?;
+        write!(out, "{}", found.as_str())?;
+        out.reset()?;
+        last = found.end();
+    }
+    writeln!(out, "{}", &line[last..])
+}
+
 pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
     contents.lines()
         .filter(|line| line.contains(query))
         .collect()
 }
 
-fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
     let query = query.to_lowercase();
     contents.lines()
         .filter(|line| line.to_lowercase().contains(&query))
         .collect()
 }
 
+pub fn search_regex<'a>(pattern: &Regex, contents: &'a str) -> Vec<&'a str> {
+    contents.lines()
+        .filter(|line| pattern.is_match(line))
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -124,4 +185,71 @@ Trust me.";
         );
     }
 
+    fn config(args: &[&str]) -> Config {
+        let args = ["minigrep"].iter().chain(args).map(|arg| arg.to_string());
+        Config::new(args).unwrap()
+    }
+
+    #[test]
+    fn regex_flag() {
+        assert!(!config(&["duct", "poem.txt"]).regex);
+        assert!(config(&["-e", "du.t", "poem.txt"]).regex);
+        assert!(config(&["du.t", "poem.txt", "--regex"]).regex);
+        assert_eq!("du.t", config(&["--regex", "du.t", "poem.txt"]).query);
+    }
+
+    #[test]
+    fn regex_results() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+        let pattern = pattern(&Config { regex: true, ..config(&["^[a-z]+,", "poem.txt"]) }).unwrap();
+
+        assert_eq!(
+            vec!["safe, fast, productive."],
+            search_regex(&pattern, contents)
+        );
+    }
+
+    #[test]
+    fn regex_case_insensitive() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+        let mut config = config(&["-e", "r.st\\b", "poem.txt"]);
+        config.case_sensitive = false;
+
+        assert_eq!(
+            vec!["Rust:", "Trust me."],
+            search_regex(&pattern(&config).unwrap(), contents)
+        );
+    }
+
+    #[test]
+    fn literal_queries_are_not_regexes() {
+        let pattern = pattern(&Config { case_sensitive: true, ..config(&["fast.", "poem.txt"]) }).unwrap();
+
+        assert!(pattern.is_match("safe, fast."));
+        assert!(!pattern.is_match("safe, fast!"));
+    }
+
+    #[test]
+    fn highlights_matches() {
+        let pattern = Regex::new("t+").unwrap();
+        let mut out = termcolor::Buffer::ansi();
+        print_highlighted(&mut out, "Trust tt.", &pattern).unwrap();
+
+        assert_eq!(
+            "Trus\x1b[0m\x1b[1m\x1b[31mt\x1b[0m \x1b[0m\x1b[1m\x1b[31mtt\x1b[0m.\n",
+            String::from_utf8(out.into_inner()).unwrap()
+        );
+
+        let mut out = termcolor::Buffer::no_color();
+        print_highlighted(&mut out, "Trust tt.", &pattern).unwrap();
+        assert_eq!("Trust tt.\n", String::from_utf8(out.into_inner()).unwrap());
+    }
 }